@@ -0,0 +1,418 @@
+use crate::{Penare, params::PenareParams, presets::{self, Preset}};
+use std::{
+    ffi::{c_char, c_void, CStr, CString},
+    io::{Cursor, Read},
+    path::Path,
+    ptr,
+    slice,
+    sync::{Arc, Mutex, OnceLock},
+};
+use clap_sys::{
+    entry::clap_plugin_entry,
+    ext::{
+        preset_load::{
+            clap_host_preset_load, clap_plugin_preset_load,
+            CLAP_EXT_PRESET_LOAD, CLAP_EXT_PRESET_LOAD_COMPAT,
+        },
+        state::{clap_plugin_state, CLAP_EXT_STATE},
+    },
+    factory::{
+        plugin_factory::{clap_plugin_factory, CLAP_PLUGIN_FACTORY_ID},
+        preset_discovery::*,
+    },
+    host::clap_host,
+    plugin::{clap_plugin, clap_plugin_descriptor},
+    stream::clap_istream,
+    universal_plugin_id::clap_universal_plugin_id,
+    version::CLAP_VERSION,
+};
+use nih_plug::{prelude::*, wrapper::{self, clap::{PluginDescriptor, Wrapper}}};
+
+// nih-plug's CLAP wrapper has no preset discovery, so instead of `nih_export_clap!`
+// the plugin exports its own entry point. It hands out nih-plug's wrapper through its
+// own plugin factory, adds the preset-load extension to the wrapper's instances and
+// has a preset-discovery factory so hosts can list the presets in their browser
+
+#[no_mangle]
+#[used]
+#[allow(non_upper_case_globals)]
+pub static clap_entry: clap_plugin_entry = clap_plugin_entry {
+    clap_version: CLAP_VERSION,
+    init: Some(init),
+    deinit: Some(deinit),
+    get_factory: Some(get_factory),
+};
+
+unsafe extern "C" fn init(_plugin_path: *const c_char) -> bool {
+    wrapper::setup_logger();
+    true
+}
+
+unsafe extern "C" fn deinit() {}
+
+unsafe extern "C" fn get_factory(factory_id: *const c_char) -> *const c_void {
+    if factory_id.is_null() {
+        return ptr::null();
+    }
+    let factory_id = CStr::from_ptr(factory_id);
+    if factory_id == CLAP_PLUGIN_FACTORY_ID {
+        ptr::from_ref(&PLUGIN_FACTORY).cast()
+    } else if factory_id == CLAP_PRESET_DISCOVERY_FACTORY_ID
+        || factory_id == CLAP_PRESET_DISCOVERY_FACTORY_ID_COMPAT
+    {
+        ptr::from_ref(&PRESET_DISCOVERY_FACTORY).cast()
+    } else {
+        ptr::null()
+    }
+}
+
+// Plugin factory
+
+static PLUGIN_FACTORY: clap_plugin_factory = clap_plugin_factory {
+    get_plugin_count: Some(get_plugin_count),
+    get_plugin_descriptor: Some(get_plugin_descriptor),
+    create_plugin: Some(create_plugin),
+};
+
+/// The wrapper's own functions that the hooks in [`create_plugin`] replace, they are
+/// the same for every instance
+struct WrapperFunctions {
+    get_extension: Option<unsafe extern "C" fn(*const clap_plugin, *const c_char) -> *const c_void>,
+    destroy: Option<unsafe extern "C" fn(*const clap_plugin)>,
+}
+
+static WRAPPER_FUNCTIONS: OnceLock<WrapperFunctions> = OnceLock::new();
+
+/// Host of every plugin instance, to tell it when a preset was loaded. The pointers
+/// are stored as addresses so they can be shared between threads
+static HOSTS: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+
+fn descriptor() -> &'static PluginDescriptor {
+    static DESCRIPTOR: OnceLock<PluginDescriptor> = OnceLock::new();
+    DESCRIPTOR.get_or_init(PluginDescriptor::for_plugin::<Penare>)
+}
+
+unsafe extern "C" fn get_plugin_count(_factory: *const clap_plugin_factory) -> u32 {
+    1
+}
+
+unsafe extern "C" fn get_plugin_descriptor(
+    _factory: *const clap_plugin_factory,
+    index: u32,
+) -> *const clap_plugin_descriptor {
+    if index == 0 {
+        ptr::from_ref(descriptor().clap_plugin_descriptor()).cast()
+    } else {
+        ptr::null()
+    }
+}
+
+unsafe extern "C" fn create_plugin(
+    _factory: *const clap_plugin_factory,
+    host: *const clap_host,
+    plugin_id: *const c_char,
+) -> *const clap_plugin {
+    if host.is_null() || plugin_id.is_null() || CStr::from_ptr(plugin_id) != descriptor().clap_id() {
+        return ptr::null();
+    }
+
+    // nih-plug and this module use different versions of clap-sys, the structs have
+    // the same C layout so the pointers are cast between them
+    let wrapper = Arc::into_raw(Wrapper::<Penare>::new(host.cast()));
+    let plugin: *mut clap_plugin = (*wrapper).clap_plugin.as_ptr().cast();
+    WRAPPER_FUNCTIONS.get_or_init(|| WrapperFunctions {
+        get_extension: (*plugin).get_extension,
+        destroy: (*plugin).destroy,
+    });
+    (*plugin).get_extension = Some(get_extension);
+    (*plugin).destroy = Some(destroy);
+    if let Ok(mut hosts) = HOSTS.lock() {
+        hosts.push((plugin as usize, host as usize));
+    }
+    plugin
+}
+
+unsafe extern "C" fn get_extension(plugin: *const clap_plugin, id: *const c_char) -> *const c_void {
+    if !id.is_null() {
+        let id = CStr::from_ptr(id);
+        if id == CLAP_EXT_PRESET_LOAD || id == CLAP_EXT_PRESET_LOAD_COMPAT {
+            return ptr::from_ref(&PRESET_LOAD).cast();
+        }
+    }
+    match WRAPPER_FUNCTIONS.get().and_then(|functions| functions.get_extension) {
+        Some(get_extension) => get_extension(plugin, id),
+        None                => ptr::null(),
+    }
+}
+
+unsafe extern "C" fn destroy(plugin: *const clap_plugin) {
+    if let Ok(mut hosts) = HOSTS.lock() {
+        hosts.retain(|(instance, _)| *instance != plugin as usize);
+    }
+    if let Some(destroy) = WRAPPER_FUNCTIONS.get().and_then(|functions| functions.destroy) {
+        destroy(plugin);
+    }
+}
+
+// Preset-load extension
+
+static PRESET_LOAD: clap_plugin_preset_load = clap_plugin_preset_load {
+    from_location: Some(from_location),
+};
+
+/// Find the preset at a location the preset-discovery provider declared. Factory
+/// presets are looked up by their name, which is their load key
+unsafe fn find_preset(location_kind: u32, location: *const c_char, load_key: *const c_char) -> Result<Preset, String> {
+    match location_kind {
+        CLAP_PRESET_DISCOVERY_LOCATION_PLUGIN if !load_key.is_null() => {
+            let name = CStr::from_ptr(load_key).to_string_lossy();
+            presets::factory()
+                .iter()
+                .find(|preset| preset.name == name)
+                .cloned()
+                .ok_or_else(|| format!("no factory preset named {}", name))
+        },
+        CLAP_PRESET_DISCOVERY_LOCATION_FILE if !location.is_null() => {
+            let path = CStr::from_ptr(location).to_string_lossy();
+            Preset::load(Path::new(path.as_ref())).map_err(|e| e.to_string())
+        },
+        _ => Err("unknown preset location".to_string()),
+    }
+}
+
+/// Load a preset through the wrapper's state extension, which sets the parameters and
+/// persistent fields and tells the host they changed
+unsafe fn load_preset(plugin: *const clap_plugin, preset: &Preset) -> Result<(), String> {
+    let get_extension = WRAPPER_FUNCTIONS.get()
+        .and_then(|functions| functions.get_extension)
+        .ok_or("the plugin isn't initialized")?;
+    let state = get_extension(plugin, CLAP_EXT_STATE.as_ptr()) as *const clap_plugin_state;
+    let load = state.as_ref().and_then(|state| state.load).ok_or("the plugin can't load states")?;
+
+    // nih-plug prepends the length of the state, CLAP streams can't tell how much
+    // data is left
+    let json = preset.to_state(&PenareParams::default()).map_err(|e| e.to_string())?;
+    let mut bytes = (json.len() as u64).to_le_bytes().to_vec();
+    bytes.extend(json);
+    let mut reader = Cursor::new(bytes);
+    let stream = clap_istream {
+        ctx: ptr::from_mut(&mut reader).cast(),
+        read: Some(read_stream),
+    };
+    if load(plugin, &stream) {
+        Ok(())
+    } else {
+        Err(format!("failed to load {}", preset.name))
+    }
+}
+
+unsafe extern "C" fn read_stream(stream: *const clap_istream, buffer: *mut c_void, size: u64) -> i64 {
+    let reader = &mut *((*stream).ctx as *mut Cursor<Vec<u8>>);
+    let buffer = slice::from_raw_parts_mut(buffer as *mut u8, size as usize);
+    reader.read(buffer).map_or(-1, |read| read as i64)
+}
+
+/// The host's preset-load extension, if it has one
+unsafe fn host_preset_load(plugin: *const clap_plugin) -> Option<(*const clap_host, &'static clap_host_preset_load)> {
+    let host = HOSTS.lock().ok()?
+        .iter()
+        .find(|(instance, _)| *instance == plugin as usize)
+        .map(|(_, host)| *host as *const clap_host)?;
+    let get_extension = (*host).get_extension?;
+    let mut extension = get_extension(host, CLAP_EXT_PRESET_LOAD.as_ptr());
+    if extension.is_null() {
+        extension = get_extension(host, CLAP_EXT_PRESET_LOAD_COMPAT.as_ptr());
+    }
+    (extension as *const clap_host_preset_load).as_ref().map(|extension| (host, extension))
+}
+
+unsafe extern "C" fn from_location(
+    plugin: *const clap_plugin,
+    location_kind: u32,
+    location: *const c_char,
+    load_key: *const c_char,
+) -> bool {
+    let result = find_preset(location_kind, location, load_key)
+        .and_then(|preset| load_preset(plugin, &preset));
+    let host = host_preset_load(plugin);
+    match result {
+        Ok(()) => {
+            if let Some((host, loaded)) = host.and_then(|(host, ext)| Some((host, ext.loaded?))) {
+                loaded(host, location_kind, location, load_key);
+            }
+            true
+        },
+        Err(e) => {
+            nih_error!("Failed to load preset: {}", e);
+            if let Some((host, on_error)) = host.and_then(|(host, ext)| Some((host, ext.on_error?))) {
+                let message = CString::new(e).unwrap_or_default();
+                on_error(host, location_kind, location, load_key, 0, message.as_ptr());
+            }
+            false
+        },
+    }
+}
+
+// Preset-discovery factory
+
+static PRESET_DISCOVERY_FACTORY: clap_preset_discovery_factory = clap_preset_discovery_factory {
+    count: Some(provider_count),
+    get_descriptor: Some(get_provider_descriptor),
+    create: Some(create_provider),
+};
+
+static PROVIDER_DESCRIPTOR: clap_preset_discovery_provider_descriptor = clap_preset_discovery_provider_descriptor {
+    clap_version: CLAP_VERSION,
+    id: c"moe.azur.penare.presets".as_ptr(),
+    name: c"Penare presets".as_ptr(),
+    vendor: c"Azur1s".as_ptr(),
+};
+
+/// A preset-discovery provider and the indexer it declares its locations to
+#[repr(C)]
+struct Provider {
+    provider: clap_preset_discovery_provider,
+    indexer: *const clap_preset_discovery_indexer,
+}
+
+unsafe extern "C" fn provider_count(_factory: *const clap_preset_discovery_factory) -> u32 {
+    1
+}
+
+unsafe extern "C" fn get_provider_descriptor(
+    _factory: *const clap_preset_discovery_factory,
+    index: u32,
+) -> *const clap_preset_discovery_provider_descriptor {
+    if index == 0 { ptr::from_ref(&PROVIDER_DESCRIPTOR) } else { ptr::null() }
+}
+
+unsafe extern "C" fn create_provider(
+    _factory: *const clap_preset_discovery_factory,
+    indexer: *const clap_preset_discovery_indexer,
+    provider_id: *const c_char,
+) -> *const clap_preset_discovery_provider {
+    if indexer.is_null() || provider_id.is_null() || CStr::from_ptr(provider_id) != CStr::from_ptr(PROVIDER_DESCRIPTOR.id) {
+        return ptr::null();
+    }
+    let provider = Box::new(Provider {
+        provider: clap_preset_discovery_provider {
+            desc: &PROVIDER_DESCRIPTOR,
+            provider_data: ptr::null_mut(),
+            init: Some(provider_init),
+            destroy: Some(provider_destroy),
+            get_metadata: Some(provider_get_metadata),
+            get_extension: Some(provider_get_extension),
+        },
+        indexer,
+    });
+    Box::into_raw(provider).cast()
+}
+
+unsafe extern "C" fn provider_init(provider: *const clap_preset_discovery_provider) -> bool {
+    let indexer = &*(*(provider as *const Provider)).indexer;
+    let (Some(declare_filetype), Some(declare_location)) = (indexer.declare_filetype, indexer.declare_location) else {
+        return false;
+    };
+
+    let extension = CString::new(presets::PRESET_EXTENSION).unwrap_or_default();
+    declare_filetype(indexer, &clap_preset_discovery_filetype {
+        name: c"Penare preset".as_ptr(),
+        description: ptr::null(),
+        file_extension: extension.as_ptr(),
+    });
+    declare_location(indexer, &clap_preset_discovery_location {
+        flags: CLAP_PRESET_DISCOVERY_IS_FACTORY_CONTENT,
+        name: c"Penare factory presets".as_ptr(),
+        kind: CLAP_PRESET_DISCOVERY_LOCATION_PLUGIN,
+        location: ptr::null(),
+    });
+    if let Some(dir) = presets::preset_dir().and_then(|dir| CString::new(dir.to_string_lossy().into_owned()).ok()) {
+        declare_location(indexer, &clap_preset_discovery_location {
+            flags: CLAP_PRESET_DISCOVERY_IS_USER_CONTENT,
+            name: c"Penare user presets".as_ptr(),
+            kind: CLAP_PRESET_DISCOVERY_LOCATION_FILE,
+            location: dir.as_ptr(),
+        });
+    }
+    true
+}
+
+unsafe extern "C" fn provider_destroy(provider: *const clap_preset_discovery_provider) {
+    drop(Box::from_raw(provider as *mut Provider));
+}
+
+unsafe extern "C" fn provider_get_extension(
+    _provider: *const clap_preset_discovery_provider,
+    _extension_id: *const c_char,
+) -> *const c_void {
+    ptr::null()
+}
+
+/// Describe a preset to the host, `load_key` is null for presets stored in their
+/// own file
+unsafe fn declare_preset(
+    receiver: &clap_preset_discovery_metadata_receiver,
+    preset: &Preset,
+    load_key: Option<&CStr>,
+    flags: u32,
+) -> bool {
+    let Some(begin_preset) = receiver.begin_preset else { return false };
+    let name = CString::new(preset.name.as_str()).unwrap_or_default();
+    if !begin_preset(receiver, name.as_ptr(), load_key.map_or(ptr::null(), CStr::as_ptr)) {
+        return false;
+    }
+    if let Some(add_plugin_id) = receiver.add_plugin_id {
+        add_plugin_id(receiver, &clap_universal_plugin_id {
+            abi: c"clap".as_ptr(),
+            id: descriptor().clap_id().as_ptr(),
+        });
+    }
+    if let Some(set_flags) = receiver.set_flags {
+        set_flags(receiver, flags);
+    }
+    if let Some(add_feature) = receiver.add_feature {
+        if let Ok(category) = CString::new(preset.category.to_lowercase()) {
+            if !preset.category.is_empty() {
+                add_feature(receiver, category.as_ptr());
+            }
+        }
+    }
+    true
+}
+
+unsafe extern "C" fn provider_get_metadata(
+    _provider: *const clap_preset_discovery_provider,
+    location_kind: u32,
+    location: *const c_char,
+    metadata_receiver: *const clap_preset_discovery_metadata_receiver,
+) -> bool {
+    let Some(receiver) = metadata_receiver.as_ref() else { return false };
+    match location_kind {
+        CLAP_PRESET_DISCOVERY_LOCATION_PLUGIN => {
+            for preset in presets::factory() {
+                let load_key = CString::new(preset.name.as_str()).unwrap_or_default();
+                if !declare_preset(receiver, preset, Some(&load_key), CLAP_PRESET_DISCOVERY_IS_FACTORY_CONTENT) {
+                    break;
+                }
+            }
+            true
+        },
+        CLAP_PRESET_DISCOVERY_LOCATION_FILE if !location.is_null() => {
+            let path = CStr::from_ptr(location).to_string_lossy();
+            match Preset::load(Path::new(path.as_ref())) {
+                Ok(preset) => {
+                    declare_preset(receiver, &preset, None, CLAP_PRESET_DISCOVERY_IS_USER_CONTENT);
+                    true
+                },
+                Err(e) => {
+                    if let Some(on_error) = receiver.on_error {
+                        let message = CString::new(e.to_string()).unwrap_or_default();
+                        on_error(receiver, 0, message.as_ptr());
+                    }
+                    false
+                },
+            }
+        },
+        _ => false,
+    }
+}
@@ -1,10 +1,87 @@
-use crate::{fxs::waveshaper::FunctionType, params::TriState};
+use crate::{fxs::{waveshaper::FunctionType, auto_gain::LearnState, spectrum}, params::TriState};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use atomic_float::AtomicF32;
 use nih_plug::prelude::*;
 use paste::paste;
 
+/// Length of the waveform history, long enough for the largest spectrum analyser FFT
+pub const WAVEFORM_LEN: usize = spectrum::MAX_FFT_SIZE;
+
+/// Lock-free history of the input and output waveforms. The audio thread is the only
+/// writer and old samples are overwritten, so the UI always sees the latest samples
+pub struct WaveformBuffer {
+    input: Vec<AtomicF32>,
+    output: Vec<AtomicF32>,
+    /// Index the next sample will be written to
+    pos: AtomicUsize,
+}
+
+impl WaveformBuffer {
+    pub fn new(len: usize) -> Self {
+        Self {
+            input: (0..len).map(|_| AtomicF32::new(0.0)).collect(),
+            output: (0..len).map(|_| AtomicF32::new(0.0)).collect(),
+            pos: AtomicUsize::new(0),
+        }
+    }
+
+    /// Push a pair of samples, called from the audio thread
+    pub fn push(&self, input: f32, output: f32) {
+        let pos = self.pos.load(Ordering::Relaxed);
+        self.input[pos].store(input, Ordering::Relaxed);
+        self.output[pos].store(output, Ordering::Relaxed);
+        self.pos.store((pos + 1) % self.input.len(), Ordering::Release);
+    }
+
+    /// Copy the waveforms into `input` and `output`, oldest sample first
+    pub fn read(&self, input: &mut [f32], output: &mut [f32]) {
+        let len = self.input.len();
+        let pos = self.pos.load(Ordering::Acquire);
+        for (i, (x, y)) in input.iter_mut().zip(output.iter_mut()).enumerate().take(len) {
+            let j = (pos + i) % len;
+            *x = self.input[j].load(Ordering::Relaxed);
+            *y = self.output[j].load(Ordering::Relaxed);
+        }
+    }
+}
+
+/// Peak and RMS levels of the first two channels for the level meters, the audio
+/// thread is the only writer
+pub struct Levels {
+    peak: [AtomicF32; 2],
+    rms: [AtomicF32; 2],
+}
+
+impl Default for Levels {
+    fn default() -> Self {
+        Self {
+            peak: [AtomicF32::new(0.0), AtomicF32::new(0.0)],
+            rms: [AtomicF32::new(0.0), AtomicF32::new(0.0)],
+        }
+    }
+}
+
+impl Levels {
+    pub fn set(&self, peak: [f32; 2], rms: [f32; 2]) {
+        for i in 0..2 {
+            self.peak[i].store(peak[i], Ordering::Relaxed);
+            self.rms[i].store(rms[i], Ordering::Relaxed);
+        }
+    }
+
+    /// Peak and RMS levels of both channels
+    pub fn get(&self) -> ([f32; 2], [f32; 2]) {
+        (
+            [0, 1].map(|i| self.peak[i].load(Ordering::Relaxed)),
+            [0, 1].map(|i| self.rms[i].load(Ordering::Relaxed)),
+        )
+    }
+}
+
 pub struct UIData {
+    /// Sample rate of the plugin, used to draw the filter response
+    pub sample_rate: AtomicF32,
+
     // ──────────────────────────────
     // Waveshaper
     // ──────────────────────────────
@@ -35,17 +112,45 @@ pub struct UIData {
     pub copy: AtomicUsize,
     /// Flip phase
     pub flip: AtomicBool,
+    /// Bias of the waveshaper, without sag or modulation
+    pub bias: AtomicF32,
+
+    // ──────────────────────────────
+    // Visualizers
+    // ──────────────────────────────
+
+    /// Number of buffers the audio thread published while the editor was open, the
+    /// visualizers redraw whenever it changes
+    pub frame: AtomicUsize,
+    /// Input (dry) and output waveforms
+    pub waveform: WaveformBuffer,
+    /// Levels before and after the plugin
+    pub input_levels: Levels,
+    /// Levels going into the waveshapers, in mid/side mode these are the mid and side
+    /// levels
+    pub drive_levels: Levels,
+    pub output_levels: Levels,
+    /// Set by the audio thread when the final clip engages, stays set until the
+    /// editor clears it
+    pub output_clipped: AtomicBool,
+    /// Fraction of the samples the final clip clipped over the last second
+    pub clip_ratio: AtomicF32,
+    /// Largest ratio between a clipped sample and the threshold over the last second
+    pub clip_overshoot: AtomicF32,
+    /// DC offset after the waveshaper, the largest over the channels
+    pub dc_offset: AtomicF32,
+    /// Gain applied by the auto gain
+    pub auto_gain: AtomicF32,
+    /// ID of the [`LearnState`] of the auto gain
+    pub auto_gain_learn_state: AtomicUsize,
 }
 
 impl Default for UIData {
     fn default() -> Self {
         let f = FunctionType::HardClip.into();
         let db = util::db_to_gain(0.0);
-        let mut l_waveform = Vec::with_capacity(1024);
-        l_waveform.resize(1024, 0.0);
-        let mut r_waveform = Vec::with_capacity(1024);
-        r_waveform.resize(1024, 0.0);
         Self {
+            sample_rate: AtomicF32::new(44100.0),
             mix: AtomicF32::new(1.0),
             input_gain: AtomicF32::new(db),
             output_gain: AtomicF32::new(db),
@@ -57,6 +162,18 @@ impl Default for UIData {
             clip_sign: AtomicBool::new(true),
             copy: AtomicUsize::new(TriState::Off.into()),
             flip: AtomicBool::new(false),
+            bias: AtomicF32::new(0.0),
+            frame: AtomicUsize::new(0),
+            waveform: WaveformBuffer::new(WAVEFORM_LEN),
+            input_levels: Levels::default(),
+            drive_levels: Levels::default(),
+            output_levels: Levels::default(),
+            output_clipped: AtomicBool::new(false),
+            clip_ratio: AtomicF32::new(0.0),
+            clip_overshoot: AtomicF32::new(0.0),
+            dc_offset: AtomicF32::new(0.0),
+            auto_gain: AtomicF32::new(1.0),
+            auto_gain_learn_state: AtomicUsize::new(LearnState::Idle.into()),
         }
     }
 }
@@ -104,6 +221,7 @@ macro_rules! set {
 
 #[allow(dead_code)]
 impl UIData {
+    get!(sample_rate          f32);
     get!(mix                  f32);
     get!(input_gain           f32);
     get!(output_gain          f32);
@@ -115,7 +233,15 @@ impl UIData {
     get!(clip_sign            bool);
     get!(copy                 TriState);
     get!(flip                 bool);
+    get!(bias                 f32);
+    get!(frame                usize);
+    get!(clip_ratio           f32);
+    get!(clip_overshoot       f32);
+    get!(dc_offset            f32);
+    get!(auto_gain            f32);
+    get!(auto_gain_learn_state LearnState);
 
+    set!(sample_rate          f32);
     set!(mix                  f32);
     set!(input_gain           f32);
     set!(output_gain          f32);
@@ -127,4 +253,15 @@ impl UIData {
     set!(clip_sign            bool);
     set!(copy                 TriState);
     set!(flip                 bool);
+    set!(bias                 f32);
+    set!(clip_ratio           f32);
+    set!(clip_overshoot       f32);
+    set!(dc_offset            f32);
+    set!(auto_gain            f32);
+    set!(auto_gain_learn_state LearnState);
+
+    /// Let the editor know there's new data to draw
+    pub fn publish_frame(&self) {
+        self.frame.fetch_add(1, Ordering::Relaxed);
+    }
 }
\ No newline at end of file
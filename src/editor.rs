@@ -1,5 +1,5 @@
-use crate::{PenareParams, data::UIData};
-use std::sync::{Arc, Mutex};
+use crate::{PenareParams, data::UIData, fxs::{auto_gain::LearnState, crossover, graph, spectrum, waveshaper}, presets};
+use std::sync::Arc;
 use nih_plug::prelude::*;
 use nih_plug_vizia::{
     vizia::prelude::*,
@@ -9,17 +9,294 @@ use nih_plug_vizia::{
 };
 
 mod waveshaper_display;
+mod oscilloscope;
+mod filter_display;
+mod patch_view;
+mod level_meter;
+mod spectrum_analyser;
 
 #[derive(Lens)]
 struct Data {
     params: Arc<PenareParams>,
-    ui_data: Arc<Mutex<UIData>>,
+    ui_data: Arc<UIData>,
+    /// Factory and user presets
+    preset_entries: Vec<presets::PresetEntry>,
+    /// The last loaded or saved preset
+    preset_current: Option<presets::PresetEntry>,
+    /// Name shown in the preset browser, also the name used when saving
+    preset_name: String,
+    /// Source of the expression function
+    expression: String,
+    /// Why the expression doesn't compile, empty if it does
+    expression_error: String,
+    /// Bumped whenever the patch changes so the patch view gets rebuilt
+    patch_version: usize,
+    /// Path of the selected patch node, empty to add nodes at the end of the patch
+    patch_selected: Vec<usize>,
+    /// Index in [`graph::NodeKind::ALL`] of the node to add
+    patch_kind: usize,
 }
 
-impl Model for Data {}
+enum PresetEvent {
+    Previous,
+    Next,
+    Save,
+    Delete,
+    SetName(String),
+}
+
+enum AutoGainEvent {
+    /// The learn mode finished listening, successfully or not
+    LearnFinished,
+}
+
+enum AnalyserEvent {
+    NextResolution,
+    NextAveraging,
+    TogglePeakHold,
+}
+
+enum ExpressionEvent {
+    Set(String),
+}
+
+enum PatchEvent {
+    Select(Vec<usize>),
+    PreviousKind,
+    NextKind,
+    /// Add a node after the selected one, in parallel with it if true
+    Insert(bool),
+    Remove,
+    /// Change the level of the parallel branch at a path by a step
+    StepLevel(Vec<usize>, f32),
+}
+
+/// Sent to the visualizers whenever the audio thread published new data
+struct FrameEvent;
+
+/// Changes to the waveshaper chain, stage 0 is the main waveshaper
+enum StageEvent {
+    Add,
+    Remove(usize),
+    MoveUp(usize),
+    MoveDown(usize),
+}
+
+impl Model for Data {
+    fn event(&mut self, cx: &mut EventContext, event: &mut Event) {
+        event.map(|preset_event, _| match preset_event {
+            PresetEvent::Previous => self.step_preset(cx, -1),
+            PresetEvent::Next     => self.step_preset(cx, 1),
+            PresetEvent::Save     => {
+                // Saving while a factory preset is shown makes a user copy of it
+                let name = match &self.preset_current {
+                    Some(entry) if entry.is_read_only() && entry.display_name() == self.preset_name => {
+                        entry.load().map(|p| p.name).unwrap_or_else(|_| self.preset_name.clone())
+                    },
+                    _ => self.preset_name.clone(),
+                };
+                let preset = presets::Preset::from_params(&name, &self.params);
+                match preset.save() {
+                    Ok(()) => {
+                        self.preset_entries = presets::entries();
+                        self.preset_current = Some(presets::PresetEntry::User(name.clone()));
+                        self.preset_name = name;
+                    },
+                    Err(e) => nih_error!("Failed to save preset {}: {}", name, e),
+                }
+            },
+            PresetEvent::Delete   => {
+                if let Some(presets::PresetEntry::User(name)) = &self.preset_current {
+                    if let Err(e) = presets::delete(name) {
+                        nih_error!("Failed to delete preset {}: {}", name, e);
+                    }
+                    self.preset_entries = presets::entries();
+                    self.preset_current = None;
+                }
+            },
+            PresetEvent::SetName(name) => self.preset_name = name.clone(),
+        });
+
+        event.map(|auto_gain_event, _| match auto_gain_event {
+            // The plugin can't change its own parameters, so the editor turns the
+            // learn button back off
+            AutoGainEvent::LearnFinished => {
+                let param = &self.params.auto_gain_learn;
+                if param.value() {
+                    cx.emit(ParamEvent::BeginSetParameter(param).upcast());
+                    cx.emit(ParamEvent::SetParameter(param, false).upcast());
+                    cx.emit(ParamEvent::EndSetParameter(param).upcast());
+                }
+            },
+        });
+
+        event.map(|analyser_event, _| {
+            if let Ok(mut settings) = self.params.analyser.write() {
+                match analyser_event {
+                    AnalyserEvent::NextResolution => settings.resolution = settings.resolution.next(),
+                    AnalyserEvent::NextAveraging  => settings.averaging = settings.averaging.next(),
+                    AnalyserEvent::TogglePeakHold => settings.peak_hold = !settings.peak_hold,
+                }
+            }
+        });
+
+        event.map(|expression_event, _| match expression_event {
+            ExpressionEvent::Set(source) => self.set_expression(source.clone()),
+        });
+
+        event.map(|stage_event, _| {
+            let count = self.params.stage_count.value() as usize;
+            match *stage_event {
+                StageEvent::Add => self.set_stage_count(cx, count + 1),
+                StageEvent::Remove(stage) if stage < count && count > 1 => {
+                    // Move the following stages up
+                    for i in stage..count - 1 {
+                        self.copy_stage(cx, i + 1, i);
+                    }
+                    self.set_stage_count(cx, count - 1);
+                },
+                StageEvent::MoveUp(stage) if stage > 0 && stage < count => self.swap_stages(cx, stage - 1, stage),
+                StageEvent::MoveDown(stage) if stage + 1 < count => self.swap_stages(cx, stage, stage + 1),
+                _ => (),
+            }
+        });
+
+        event.map(|patch_event, _| match patch_event {
+            PatchEvent::Select(path) => {
+                // Clicking the selected node again selects the whole patch
+                self.patch_selected = if *path == self.patch_selected { Vec::new() } else { path.clone() };
+                self.patch_version += 1;
+            },
+            PatchEvent::PreviousKind => {
+                let len = graph::NodeKind::ALL.len();
+                self.patch_kind = (self.patch_kind + len - 1) % len;
+            },
+            PatchEvent::NextKind => self.patch_kind = (self.patch_kind + 1) % graph::NodeKind::ALL.len(),
+            PatchEvent::Insert(parallel) => {
+                let kind = graph::NodeKind::ALL[self.patch_kind];
+                let selected = self.patch_selected.clone();
+                if let Some(Some(path)) = self.edit_patch(|patch| patch.insert(&selected, kind, *parallel)) {
+                    self.patch_selected = path;
+                }
+            },
+            PatchEvent::Remove => {
+                // An empty path is the whole patch, so only remove what's selected
+                if !self.patch_selected.is_empty() {
+                    let selected = std::mem::take(&mut self.patch_selected);
+                    self.edit_patch(|patch| patch.remove(&selected));
+                }
+            },
+            PatchEvent::StepLevel(path, step) => {
+                self.edit_patch(|patch| {
+                    if let Some(level) = patch.level(path) {
+                        // Round so repeated steps land on even levels
+                        patch.set_level(path, ((level + step) * 100.0).round() / 100.0);
+                    }
+                });
+            },
+        });
+    }
+}
+
+impl Data {
+    /// Load the preset `step` places away from the current one
+    fn step_preset(&mut self, cx: &mut EventContext, step: isize) {
+        if self.preset_entries.is_empty() {
+            return;
+        }
+        let len = self.preset_entries.len() as isize;
+        let current = self.preset_current.as_ref()
+            .and_then(|current| self.preset_entries.iter().position(|entry| entry == current));
+        let index = match current {
+            Some(index) => (index as isize + step).rem_euclid(len),
+            None        => 0,
+        };
+        let entry = self.preset_entries[index as usize].clone();
+        self.load_preset(cx, entry);
+    }
+
+    /// Load a preset, setting every parameter through the host
+    fn load_preset(&mut self, cx: &mut EventContext, entry: presets::PresetEntry) {
+        match entry.load() {
+            Ok(preset) => {
+                for (ptr, value) in preset.values(&self.params) {
+                    set_parameter(cx, ptr, value);
+                }
+                self.params.deserialize_fields(&preset.fields);
+                self.params.render_custom_curve();
+                self.params.compile_patch();
+                self.patch_selected.clear();
+                self.patch_version += 1;
+                let source = self.params.expression.read().map(|s| s.clone()).unwrap_or_default();
+                self.set_expression(source);
+                self.preset_name = entry.display_name();
+                self.preset_current = Some(entry);
+            },
+            Err(e) => nih_error!("Failed to load preset {}: {}", entry.display_name(), e),
+        }
+    }
+
+    fn set_stage_count(&self, cx: &mut EventContext, count: usize) {
+        let param = &self.params.stage_count;
+        let count = count.clamp(1, waveshaper::MAX_STAGES) as i32;
+        cx.emit(ParamEvent::BeginSetParameter(param).upcast());
+        cx.emit(ParamEvent::SetParameter(param, count).upcast());
+        cx.emit(ParamEvent::EndSetParameter(param).upcast());
+    }
+
+    /// Copy the settings of a waveshaper stage to another
+    fn copy_stage(&self, cx: &mut EventContext, from: usize, to: usize) {
+        for (from, to) in self.params.stage_ptrs(from).into_iter().zip(self.params.stage_ptrs(to)) {
+            set_parameter(cx, to, unsafe { from.unmodulated_normalized_value() });
+        }
+    }
+
+    /// Swap the settings of two waveshaper stages
+    fn swap_stages(&self, cx: &mut EventContext, a: usize, b: usize) {
+        for (a, b) in self.params.stage_ptrs(a).into_iter().zip(self.params.stage_ptrs(b)) {
+            let (a_value, b_value) = unsafe {
+                (a.unmodulated_normalized_value(), b.unmodulated_normalized_value())
+            };
+            set_parameter(cx, a, b_value);
+            set_parameter(cx, b, a_value);
+        }
+    }
+
+    /// Compile a new expression, keeping the error to show under the text box. Called
+    /// when the text box is submitted rather than on every keystroke. Text that doesn't
+    /// compile only stays in the text box, the parameters keep the last working source
+    fn set_expression(&mut self, source: String) {
+        self.expression_error = match self.params.set_expression(&source) {
+            Ok(())  => String::new(),
+            Err(e)  => e.to_string(),
+        };
+        self.expression = source;
+    }
+
+    /// Change the patch and swap it into the audio thread
+    fn edit_patch<T>(&mut self, edit: impl FnOnce(&mut graph::Route) -> T) -> Option<T> {
+        let result = edit(&mut *self.params.patch.write().ok()?);
+        self.params.compile_patch();
+        self.patch_version += 1;
+        Some(result)
+    }
+}
+
+/// Send a [`FrameEvent`] to the view being built whenever the audio thread publishes
+/// new data, parameter changes alone don't happen while audio plays
+fn redraw_on_frame(cx: &mut Context) {
+    Binding::new(cx, Data::ui_data.map(|ui_data| ui_data.get_frame()), |cx, _| cx.emit(FrameEvent));
+}
+
+/// Set a parameter through the host
+fn set_parameter(cx: &mut EventContext, ptr: ParamPtr, normalized: f32) {
+    cx.emit(RawParamEvent::BeginSetParameter(ptr));
+    cx.emit(RawParamEvent::SetParameterNormalized(ptr, normalized));
+    cx.emit(RawParamEvent::EndSetParameter(ptr));
+}
 
 pub(crate) fn default_state() -> Arc<ViziaState> {
-    ViziaState::new(|| (400, 700))
+    ViziaState::new(|| (400, 800))
 }
 
 // Fonts
@@ -27,7 +304,7 @@ const FONT_REGULAR: &[u8] = include_bytes!("../assets/PhlattGrotesk-Regular.ttf"
 
 pub(crate) fn create(
     params: Arc<PenareParams>,
-    ui_data: Arc<Mutex<UIData>>,
+    ui_data: Arc<UIData>,
     editor_state: Arc<ViziaState>,
 ) -> Option<Box<dyn Editor>> {
     create_vizia_editor(editor_state, nih_plug_vizia::ViziaTheming::Custom, move |cx, _| {
@@ -42,13 +319,80 @@ pub(crate) fn create(
         Data {
             params: params.clone(),
             ui_data: ui_data.clone(),
+            preset_entries: presets::entries(),
+            preset_current: None,
+            preset_name: String::from("init"),
+            expression: params.expression.read().map(|s| s.clone()).unwrap_or_default(),
+            expression_error: params.compile_expression().err().map(|e| e.to_string()).unwrap_or_default(),
+            patch_version: 0,
+            patch_selected: Vec::new(),
+            patch_kind: 0,
         }.build(cx);
 
         ResizeHandle::new(cx);
 
         VStack::new(cx, |cx| {
-            waveshaper_display::WaveshaperDisplay::new(
+            // Preset browser
+            HStack::new(cx, |cx| {
+                Button::new(cx, |cx| cx.emit(PresetEvent::Previous), |cx| Label::new(cx, "<"));
+                Textbox::new(cx, Data::preset_name)
+                    .on_edit(|cx, text| cx.emit(PresetEvent::SetName(text)))
+                    .width(Stretch(1.0));
+                Button::new(cx, |cx| cx.emit(PresetEvent::Next), |cx| Label::new(cx, ">"));
+                Button::new(cx, |cx| cx.emit(PresetEvent::Save), |cx| Label::new(cx, "save"));
+                Button::new(cx, |cx| cx.emit(PresetEvent::Delete), |cx| Label::new(cx, "delete"));
+            })
+            .class("presets")
+            .width(Percentage(100.0))
+            .height(Pixels(30.0))
+            .child_top(Stretch(1.0))
+            .child_bottom(Stretch(1.0))
+            .col_between(Pixels(4.0));
+
+            HStack::new(cx, |cx| {
+                waveshaper_display::WaveshaperDisplay::new(
+                    cx,
+                    Data::params,
+                    Data::ui_data,
+                )
+                .width(Percentage(50.0))
+                .height(Pixels(100.0));
+
+                oscilloscope::Oscilloscope::new(
+                    cx,
+                    Data::ui_data,
+                )
+                .width(Percentage(50.0))
+                .height(Pixels(100.0));
+            })
+            .width(Percentage(100.0))
+            .height(Pixels(100.0));
+
+            filter_display::FilterDisplay::new(
+                cx,
+                Data::params,
+                Data::ui_data,
+            )
+            .width(Percentage(100.0))
+            .height(Pixels(100.0));
+
+            HStack::new(cx, |cx| {
+                for source in [
+                    level_meter::MeterSource::Input,
+                    level_meter::MeterSource::Drive,
+                    level_meter::MeterSource::Output,
+                ] {
+                    level_meter::LevelMeter::new(cx, Data::ui_data, source)
+                        .width(Stretch(1.0))
+                        .height(Pixels(20.0));
+                }
+            })
+            .width(Percentage(100.0))
+            .height(Pixels(20.0));
+
+            spectrum_analyser::SpectrumAnalyser::new(
                 cx,
+                Data::params,
                 Data::ui_data,
             )
             .width(Percentage(100.0))
@@ -65,18 +409,88 @@ pub(crate) fn create(
                 };
             }
             macro_rules! slider {
-                ($cx:ident, $label:expr, $param:ident) => {
+                ($cx:ident, $label:expr, $($param:ident).+) => {
                     hstack!($cx, |cx| {
-                        ParamSlider::new(cx, Data::params, |p| &p.$param)
+                        ParamSlider::new(cx, Data::params, |p| &p.$($param).+)
                         .height(Pixels(36.0));
                         Label::new(cx, $label);
                     })
                 };
             }
             macro_rules! button {
-                ($cx:ident, $label:expr, $param:ident) => {
+                ($cx:ident, $label:expr, $($param:ident).+) => {
                     hstack!($cx, |cx| {
-                        ParamButton::new(cx, Data::params, |p| &p.$param)
+                        ParamButton::new(cx, Data::params, |p| &p.$($param).+)
+                        .height(Pixels(36.0));
+                        Label::new(cx, $label);
+                    })
+                };
+            }
+            // Band parameters are indexed, so the lens closure has to own the index
+            macro_rules! band_slider {
+                ($cx:ident, $label:expr, $i:expr, $param:ident) => {
+                    let i = $i;
+                    hstack!($cx, move |cx| {
+                        ParamSlider::new(cx, Data::params, move |p| &p.bands[i].$param)
+                        .height(Pixels(36.0));
+                        Label::new(cx, $label);
+                    })
+                };
+            }
+            macro_rules! band_button {
+                ($cx:ident, $label:expr, $i:expr, $param:ident) => {
+                    let i = $i;
+                    hstack!($cx, move |cx| {
+                        ParamButton::new(cx, Data::params, move |p| &p.bands[i].$param)
+                        .height(Pixels(36.0));
+                        Label::new(cx, $label);
+                    })
+                };
+            }
+            macro_rules! channel_slider {
+                ($cx:ident, $label:expr, $i:expr, $param:ident) => {
+                    let i = $i;
+                    hstack!($cx, move |cx| {
+                        ParamSlider::new(cx, Data::params, move |p| &p.channels[i].$param)
+                        .height(Pixels(36.0));
+                        Label::new(cx, $label);
+                    })
+                };
+            }
+            // Stages after the main waveshaper are indexed too
+            macro_rules! stage_slider {
+                ($cx:ident, $label:expr, $i:expr, $param:ident) => {
+                    let i = $i;
+                    hstack!($cx, move |cx| {
+                        ParamSlider::new(cx, Data::params, move |p| &p.stages()[i].$param)
+                        .height(Pixels(36.0));
+                        Label::new(cx, $label);
+                    })
+                };
+            }
+            macro_rules! stage_buttons {
+                ($cx:ident, $i:expr) => {
+                    let i = $i;
+                    hstack!($cx, move |cx| {
+                        Button::new(cx, move |cx| cx.emit(StageEvent::MoveUp(i)), |cx| Label::new(cx, "up"));
+                        Button::new(cx, move |cx| cx.emit(StageEvent::MoveDown(i)), |cx| Label::new(cx, "down"));
+                        Button::new(cx, move |cx| cx.emit(StageEvent::Remove(i)), |cx| Label::new(cx, "remove"));
+                    })
+                    .height(Pixels(30.0))
+                };
+            }
+            // The analyser settings aren't parameters, their buttons cycle through
+            // the options
+            macro_rules! analyser_button {
+                ($cx:ident, $label:expr, $event:expr, $text:expr) => {
+                    hstack!($cx, |cx| {
+                        Button::new(cx, |cx| cx.emit($event), |cx| {
+                            Label::new(cx, Data::params.map(|params| {
+                                let text: fn(&spectrum::Settings) -> String = $text;
+                                params.analyser.read().map(|settings| text(&settings)).unwrap_or_default()
+                            }))
+                        })
+                        .width(Pixels(180.0))
                         .height(Pixels(36.0));
                         Label::new(cx, $label);
                     })
@@ -101,12 +515,87 @@ pub(crate) fn create(
                     slider!(cx, "mix", mix);
                     button!(cx, "hard clip output", output_clip);
                     slider!(cx, "output clip threshold", output_clip_threshold);
+                    hstack!(cx, |cx| {
+                        // How much the final clip engaged over the last second
+                        Label::new(cx, Data::ui_data.map(|ui_data| {
+                            let overshoot = ui_data.get_clip_overshoot();
+                            if overshoot > 0.0 {
+                                format!(
+                                    "{:.1}% (+{:.1} dB)",
+                                    ui_data.get_clip_ratio() * 100.0,
+                                    util::gain_to_db(overshoot),
+                                )
+                            } else {
+                                String::from("0.0%")
+                            }
+                        }))
+                        .width(Pixels(180.0));
+                        Label::new(cx, "clip activity");
+                    })
+                    .height(Pixels(36.0));
                     slider!(cx, "input gain", input_gain);
                     slider!(cx, "output gain", output_gain);
+                    slider!(cx, "auto gain", auto_gain);
+                    slider!(cx, "loudness", auto_gain_loudness);
+                    button!(cx, "learn", auto_gain_learn);
+                    hstack!(cx, |cx| {
+                        Label::new(cx, Data::ui_data.map(|ui_data| {
+                            if ui_data.get_auto_gain_learn_state() == LearnState::Learning {
+                                String::from("learning...")
+                            } else {
+                                format!("{:+.1} dB", util::gain_to_db(ui_data.get_auto_gain()))
+                            }
+                        }))
+                        .width(Pixels(180.0));
+                        Label::new(cx, "compensation");
+                    })
+                    .height(Pixels(36.0));
+                    // Bound to the state's ID, enums outside of the editor aren't vizia data
+                    let learn_state = Data::ui_data.map(|ui_data| usize::from(ui_data.get_auto_gain_learn_state()));
+                    Binding::new(cx, learn_state, |cx, state| {
+                        let state = LearnState::from(state.get(cx));
+                        if matches!(state, LearnState::Learned | LearnState::TooQuiet) {
+                            cx.emit(AutoGainEvent::LearnFinished);
+                        }
+                        if state == LearnState::TooQuiet {
+                            hstack!(cx, |cx| {
+                                Label::new(cx, "input too quiet to learn, play something louder").class("error");
+                            });
+                        }
+                    });
+
+                    // Stereo parameters
+                    header!(cx, "stereo");
+                    slider!(cx, "mode", stereo_mode);
+                    for (i, name) in ["mid/left", "side/right"].into_iter().enumerate() {
+                        channel_slider!(cx, &format!("{} drive", name), i, drive);
+                        channel_slider!(cx, &format!("{} mix", name), i, mix);
+                    }
+                    button!(cx, "link", stereo_link);
+                    slider!(cx, "offset", stereo_offset);
+                    header!(cx, "side/right (unlinked)");
+                    slider!(cx, "+ function type", unlinked.pos_function_type);
+                    slider!(cx, "+ function parameter", unlinked.pos_function_param);
+                    slider!(cx, "- function type", unlinked.neg_function_type);
+                    slider!(cx, "- function parameter", unlinked.neg_function_param);
+                    slider!(cx, "filter 1 type", unlinked.f1_type);
+                    slider!(cx, "filter 1 freq", unlinked.f1_freq);
+                    slider!(cx, "filter 1 q", unlinked.f1_q);
+                    slider!(cx, "filter 2 type", unlinked.f2_type);
+                    slider!(cx, "filter 2 freq", unlinked.f2_freq);
+                    slider!(cx, "filter 2 q", unlinked.f2_q);
 
                     // Distortions parameter
                     header!(cx, "waveshaper");
+                    slider!(cx, "stages", stage_count);
+                    hstack!(cx, |cx| {
+                        Button::new(cx, |cx| cx.emit(StageEvent::Add), |cx| Label::new(cx, "add stage"));
+                    })
+                    .height(Pixels(30.0));
+                    stage_buttons!(cx, 0);
                     slider!(cx, "function mix", function_mix);
+                    slider!(cx, "bias", bias);
+                    slider!(cx, "bias sag", bias_sag);
                     slider!(cx, "+ function type", pos_function_type);
                     slider!(cx, "+ function parameter", pos_function_param);
                     slider!(cx, "+ function mix", pos_function_mix);
@@ -116,17 +605,139 @@ pub(crate) fn create(
                     slider!(cx, "clip sign", clip_sign);
                     slider!(cx, "copy from", copy_function);
                     button!(cx, "flip phase", flip);
+                    slider!(cx, "oversampling", oversampling);
+                    slider!(cx, "anti-aliasing", quality);
+                    button!(cx, "dc blocker", dc_block);
+                    hstack!(cx, |cx| {
+                        // Read straight from the audio thread so it keeps moving while
+                        // no parameter changes
+                        Label::new(cx, Data::ui_data.map(|ui_data| {
+                            let offset = ui_data.get_dc_offset();
+                            format!("{:+.3} ({:.1} dB)", offset, util::gain_to_db(offset.abs()))
+                        }))
+                        .width(Pixels(180.0));
+                        Label::new(cx, "dc offset");
+                    })
+                    .height(Pixels(36.0));
+                    hstack!(cx, |cx| {
+                        Textbox::new(cx, Data::expression)
+                            .on_submit(|cx, text, _| cx.emit(ExpressionEvent::Set(text)))
+                            .width(Pixels(180.0))
+                            .height(Pixels(36.0));
+                        Label::new(cx, "expression");
+                    });
+                    hstack!(cx, |cx| {
+                        Label::new(cx, Data::expression_error).class("error");
+                    });
+
+                    // Waveshaper chain, these run after the main waveshaper. Only the
+                    // stages in use are shown
+                    for i in 0..waveshaper::MAX_STAGES - 1 {
+                        VStack::new(cx, move |cx| {
+                            header!(cx, format!("stage {}", i + 2));
+                            stage_buttons!(cx, i + 1);
+                            stage_slider!(cx, "function mix", i, function_mix);
+                            stage_slider!(cx, "+ function type", i, pos_function_type);
+                            stage_slider!(cx, "+ function parameter", i, pos_function_param);
+                            stage_slider!(cx, "+ function mix", i, pos_function_mix);
+                            stage_slider!(cx, "- function type", i, neg_function_type);
+                            stage_slider!(cx, "- function parameter", i, neg_function_param);
+                            stage_slider!(cx, "- function mix", i, neg_function_mix);
+                            stage_slider!(cx, "clip sign", i, clip_sign);
+                            stage_slider!(cx, "copy from", i, copy_function);
+                            stage_slider!(cx, "flip phase", i, flip);
+                        })
+                        .height(Auto)
+                        .display(Data::params.map(move |params| params.stage_count.value() as usize > i + 1));
+                    }
+
+                    // Feedback parameters
+                    header!(cx, "feedback");
+                    slider!(cx, "feedback", feedback);
+                    slider!(cx, "delay", feedback_delay);
+                    slider!(cx, "damping", feedback_damping);
 
                     // Filters parameters
                     header!(cx, "filter");
-                    slider!(cx, "excess mix", excess_mix);
+                    // The patch routes the excess signals itself
+                    slider!(cx, "excess mix", excess_mix)
+                        .class("excess-control")
+                        .disabled(Data::params.map(|params| params.patch_mode.value()));
                     slider!(cx, "filter 1 type", f1_type);
                     slider!(cx, "filter 1 freq", f1_freq);
                     slider!(cx, "filter 1 q", f1_q);
                     slider!(cx, "filter 2 type", f2_type);
                     slider!(cx, "filter 2 freq", f2_freq);
                     slider!(cx, "filter 2 q", f2_q);
-                    button!(cx, "excess bypass", excess_bypass);
+                    button!(cx, "excess bypass", excess_bypass)
+                        .class("excess-control")
+                        .disabled(Data::params.map(|params| params.patch_mode.value()));
+
+                    // Patch
+                    header!(cx, "patch");
+                    button!(cx, "patch mode", patch_mode);
+                    patch_view::PatchView::new(cx, Data::params)
+                        .width(Percentage(100.0))
+                        .height(Auto);
+
+                    // Multiband parameters
+                    header!(cx, "multiband");
+                    button!(cx, "multiband", multiband);
+                    slider!(cx, "bands", band_count);
+                    slider!(cx, "crossover 1", crossover_1);
+                    slider!(cx, "crossover 2", crossover_2);
+                    slider!(cx, "crossover 3", crossover_3);
+                    for i in 0..crossover::MAX_BANDS {
+                        header!(cx, format!("band {}", i + 1));
+                        band_slider!(cx, "gain", i, gain);
+                        band_slider!(cx, "+ function type", i, pos_function_type);
+                        band_slider!(cx, "+ function parameter", i, pos_function_param);
+                        band_slider!(cx, "- function type", i, neg_function_type);
+                        band_slider!(cx, "- function parameter", i, neg_function_param);
+                        band_slider!(cx, "mix", i, mix);
+                        band_button!(cx, "solo", i, solo);
+                        band_button!(cx, "mute", i, mute);
+                        band_slider!(cx, "level", i, level);
+                    }
+
+                    // Modulation parameters
+                    header!(cx, "lfo 1");
+                    slider!(cx, "shape", lfo1.shape);
+                    button!(cx, "tempo sync", lfo1.sync);
+                    slider!(cx, "rate", lfo1.rate);
+                    slider!(cx, "sync rate", lfo1.sync_rate);
+                    slider!(cx, "depth", lfo1.depth);
+                    slider!(cx, "destination", lfo1.destination);
+                    header!(cx, "lfo 2");
+                    slider!(cx, "shape", lfo2.shape);
+                    button!(cx, "tempo sync", lfo2.sync);
+                    slider!(cx, "rate", lfo2.rate);
+                    slider!(cx, "sync rate", lfo2.sync_rate);
+                    slider!(cx, "depth", lfo2.depth);
+                    slider!(cx, "destination", lfo2.destination);
+                    header!(cx, "envelope follower");
+                    slider!(cx, "source", env.source);
+                    slider!(cx, "attack", env.attack);
+                    slider!(cx, "release", env.release);
+                    slider!(cx, "mode", env.mode);
+                    slider!(cx, "amount", env.amount);
+                    slider!(cx, "destination", env.destination);
+                    header!(cx, "adsr (midi)");
+                    slider!(cx, "attack", adsr.attack);
+                    slider!(cx, "decay", adsr.decay);
+                    slider!(cx, "sustain", adsr.sustain);
+                    slider!(cx, "release", adsr.release);
+                    slider!(cx, "velocity", adsr.velocity);
+                    slider!(cx, "amount", adsr.amount);
+                    slider!(cx, "destination", adsr.destination);
+
+                    // Spectrum analyser
+                    header!(cx, "analyser");
+                    analyser_button!(cx, "resolution", AnalyserEvent::NextResolution, |settings| settings.resolution.name());
+                    analyser_button!(cx, "averaging", AnalyserEvent::NextAveraging, |settings| settings.averaging.name());
+                    analyser_button!(cx, "peak hold", AnalyserEvent::TogglePeakHold, |settings| {
+                        String::from(if settings.peak_hold { "on" } else { "off" })
+                    });
                 })
                 .class("params");
             })
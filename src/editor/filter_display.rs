@@ -0,0 +1,244 @@
+use crate::{PenareParams, data::UIData, fxs::filter::Biquad};
+use std::sync::Arc;
+use nih_plug::prelude::*;
+use nih_plug_vizia::{
+    vizia::{prelude::*, vg},
+    widgets::{ParamEvent, RawParamEvent},
+};
+
+/// Frequency range shown on the X axis
+const MIN_FREQ: f32 = 20.0;
+const MAX_FREQ: f32 = 22000.0;
+/// Gain range shown on the Y axis, in dB
+const MIN_DB: f32 = -30.0;
+const MAX_DB: f32 = 24.0;
+/// Q range the nodes can be dragged across
+const MIN_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+const MAX_Q: f32 = 10.0;
+/// Radius of the nodes
+const NODE_RADIUS: f32 = 5.0;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Node {
+    F1,
+    F2,
+}
+
+pub struct FilterDisplay {
+    params: Arc<PenareParams>,
+    ui_data: Arc<UIData>,
+    /// Node being dragged
+    dragging: Option<Node>,
+    /// Last mouse position relative to the view
+    mouse: (f32, f32),
+}
+
+impl FilterDisplay {
+    /// Create a new filter display
+    pub fn new<LParams, LUIData>(
+        cx: &mut Context,
+        params: LParams,
+        ui_data: LUIData,
+    ) -> Handle<Self> where
+        LParams: Lens<Target = Arc<PenareParams>>,
+        LUIData: Lens<Target = Arc<UIData>>,
+    {
+        Self {
+            params: params.get(cx),
+            ui_data: ui_data.get(cx),
+            dragging: None,
+            mouse: (0.0, 0.0),
+        }.build(cx, |_cx| ())
+    }
+
+    /// The filter's frequency and Q parameters
+    fn node_params(&self, node: Node) -> (&FloatParam, &FloatParam) {
+        match node {
+            Node::F1 => (&self.params.f1_freq, &self.params.f1_q),
+            Node::F2 => (&self.params.f2_freq, &self.params.f2_q),
+        }
+    }
+
+    /// Position of a node relative to the view, the frequency on the X axis and
+    /// the Q on the Y axis
+    fn node_position(&self, node: Node, bounds: BoundingBox) -> (f32, f32) {
+        let (freq, q) = self.node_params(node);
+        (
+            freq_to_x(freq.value(), bounds.w),
+            (1.0 - log_normalize(q.value(), MIN_Q, MAX_Q)) * bounds.h,
+        )
+    }
+
+    /// Build the filters as they are in the audio thread
+    fn filters(&self) -> [Biquad; 2] {
+        let sample_rate = self.ui_data.get_sample_rate();
+        let build = |ty, freq, q| {
+            let mut filter = Biquad::default();
+            filter.filter_type = ty;
+            filter.freq = freq;
+            filter.q = q;
+            filter.sample_rate = sample_rate;
+            filter.calculate_coeff();
+            filter
+        };
+        [
+            build(self.params.f1_type.value(), self.params.f1_freq.value(), self.params.f1_q.value()),
+            build(self.params.f2_type.value(), self.params.f2_freq.value(), self.params.f2_q.value()),
+        ]
+    }
+}
+
+/// Map a value between `min` and `max` to [0, 1] on a logarithmic scale
+fn log_normalize(x: f32, min: f32, max: f32) -> f32 {
+    ((x / min).ln() / (max / min).ln()).clamp(0.0, 1.0)
+}
+
+/// Inverse of [`log_normalize`]
+fn log_unnormalize(t: f32, min: f32, max: f32) -> f32 {
+    min * (max / min).powf(t.clamp(0.0, 1.0))
+}
+
+fn freq_to_x(freq: f32, width: f32) -> f32 {
+    log_normalize(freq, MIN_FREQ, MAX_FREQ) * width
+}
+
+fn db_to_y(db: f32, height: f32) -> f32 {
+    (1.0 - (db.clamp(MIN_DB, MAX_DB) - MIN_DB) / (MAX_DB - MIN_DB)) * height
+}
+
+impl View for FilterDisplay {
+    fn element(&self) -> Option<&'static str> {
+        Some("filter-display")
+    }
+
+    fn event(&mut self, cx: &mut EventContext, event: &mut Event) {
+        event.map(|window_event, meta| match window_event {
+            WindowEvent::MouseDown(MouseButton::Left) => {
+                let bounds = cx.bounds();
+                let radius = NODE_RADIUS * 3.0;
+                let (mx, my) = self.mouse;
+                // Pick the closest node under the cursor
+                let node = [Node::F1, Node::F2]
+                    .into_iter()
+                    .map(|node| {
+                        let (x, y) = self.node_position(node, bounds);
+                        (node, (x - mx).hypot(y - my))
+                    })
+                    .filter(|(_, distance)| *distance <= radius)
+                    .min_by(|a, b| a.1.total_cmp(&b.1))
+                    .map(|(node, _)| node);
+
+                if let Some(node) = node {
+                    let (freq, q) = self.node_params(node);
+                    cx.emit(ParamEvent::BeginSetParameter(freq).upcast());
+                    cx.emit(ParamEvent::BeginSetParameter(q).upcast());
+                    self.dragging = Some(node);
+                    cx.capture();
+                    meta.consume();
+                }
+            },
+            WindowEvent::MouseUp(MouseButton::Left) => {
+                if let Some(node) = self.dragging.take() {
+                    let (freq, q) = self.node_params(node);
+                    cx.emit(ParamEvent::EndSetParameter(freq).upcast());
+                    cx.emit(ParamEvent::EndSetParameter(q).upcast());
+                    cx.release();
+                    meta.consume();
+                }
+            },
+            WindowEvent::MouseMove(x, y) => {
+                let bounds = cx.bounds();
+                self.mouse = (x - bounds.x, y - bounds.y);
+                if let Some(node) = self.dragging {
+                    let (freq, q) = self.node_params(node);
+                    let freq_value = log_unnormalize(self.mouse.0 / bounds.w, MIN_FREQ, MAX_FREQ);
+                    let q_value = log_unnormalize(1.0 - self.mouse.1 / bounds.h, MIN_Q, MAX_Q);
+                    cx.emit(ParamEvent::SetParameter(freq, freq_value).upcast());
+                    cx.emit(ParamEvent::SetParameter(q, q_value).upcast());
+                }
+            },
+            _ => {},
+        });
+
+        event.map(|e, _| match e {
+            RawParamEvent::ParametersChanged => cx.needs_redraw(),
+            _ => (),
+        });
+    }
+
+    fn draw(&self, cx: &mut DrawContext, canvas: &mut Canvas) {
+        let bounds = cx.bounds();
+        if bounds.w == 0.0 || bounds.h == 0.0 {
+            return;
+        }
+
+        // Draw relative to the view
+        canvas.save();
+        canvas.translate(bounds.x, bounds.y);
+
+        let line_width = cx.style.dpi_factor as f32 * 1.5;
+        let border_color: vg::Color = cx.border_color().cloned().unwrap_or_default().into();
+        let font_color: vg::Color = cx.font_color().cloned().unwrap_or_default().into();
+
+        // Draw background color
+        let mut path = vg::Path::new();
+        let paint = vg::Paint::color(cx.background_color().cloned().unwrap_or_default().into());
+        path.rect(0.0, 0.0, bounds.w, bounds.h);
+        canvas.fill_path(&mut path, &paint);
+
+        // Draw 0 dB and decade lines
+        let mut path = vg::Path::new();
+        let zero = db_to_y(0.0, bounds.h);
+        path.move_to(0.0, zero);
+        path.line_to(bounds.w, zero);
+        for freq in [100.0, 1000.0, 10000.0] {
+            let x = freq_to_x(freq, bounds.w);
+            path.move_to(x, 0.0);
+            path.line_to(x, bounds.h);
+        }
+        canvas.stroke_path(&mut path, &vg::Paint::color(border_color).with_line_width(line_width * 0.5));
+
+        // Combined response of both filters
+        let [f1, f2] = self.filters();
+        let mut curve = vg::Path::new();
+        for x in 0..=(bounds.w as usize) {
+            let x = x as f32;
+            let freq = log_unnormalize(x / bounds.w, MIN_FREQ, MAX_FREQ);
+            let magnitude = f1.magnitude(freq) * f2.magnitude(freq);
+            let y = db_to_y(util::gain_to_db(magnitude), bounds.h);
+            if x == 0.0 {
+                curve.move_to(x, y);
+            } else {
+                curve.line_to(x, y);
+            }
+        }
+
+        // Shade the excess band (between the response and 0 dB), stronger the more
+        // of it gets mixed back in
+        let excess = if self.params.excess_bypass.value() {
+            1.0
+        } else {
+            self.params.excess_mix.value()
+        };
+        let mut shade = curve.clone();
+        shade.line_to(bounds.w, zero);
+        shade.line_to(0.0, zero);
+        shade.close();
+        let mut shade_color = font_color;
+        shade_color.a = 0.1 + 0.4 * excess;
+        canvas.fill_path(&mut shade, &vg::Paint::color(shade_color));
+
+        canvas.stroke_path(&mut curve, &vg::Paint::color(font_color).with_line_width(line_width));
+
+        // Nodes
+        for node in [Node::F1, Node::F2] {
+            let (x, y) = self.node_position(node, bounds);
+            let mut path = vg::Path::new();
+            path.circle(x, y, NODE_RADIUS * cx.style.dpi_factor as f32);
+            let color = if self.dragging == Some(node) { font_color } else { border_color };
+            canvas.fill_path(&mut path, &vg::Paint::color(color));
+        }
+
+        canvas.restore();
+    }
+}
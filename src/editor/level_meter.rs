@@ -0,0 +1,158 @@
+use crate::data::UIData;
+use super::FrameEvent;
+use std::{cell::Cell, sync::{Arc, atomic::Ordering}, time::{Duration, Instant}};
+use nih_plug::prelude::*;
+use nih_plug_vizia::vizia::{prelude::*, vg};
+
+/// Level range shown, in dB
+const MIN_DB: f32 = -60.0;
+const MAX_DB: f32 = 6.0;
+/// How long the peak hold stays before following the peak again
+const HOLD_TIME: Duration = Duration::from_millis(1500);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MeterSource {
+    Input,
+    /// The signal going into the waveshapers
+    Drive,
+    Output,
+}
+
+/// Horizontal meter for the first two channels. The peak level is drawn behind the
+/// RMS level, with a tick for the held peak. The output meter also has a clip LED
+/// that lights up when the final clip engaged, click the meter to clear it
+pub struct LevelMeter {
+    ui_data: Arc<UIData>,
+    source: MeterSource,
+    /// Held peak of each channel and when it was set
+    hold: Cell<[(f32, Instant); 2]>,
+}
+
+impl LevelMeter {
+    pub fn new<LUIData>(
+        cx: &mut Context,
+        ui_data: LUIData,
+        source: MeterSource,
+    ) -> Handle<Self> where
+        LUIData: Lens<Target = Arc<UIData>>,
+    {
+        Self {
+            ui_data: ui_data.get(cx),
+            source,
+            hold: Cell::new([(0.0, Instant::now()); 2]),
+        }.build(cx, super::redraw_on_frame)
+    }
+}
+
+fn db_to_x(level: f32, width: f32) -> f32 {
+    let db = util::gain_to_db(level).clamp(MIN_DB, MAX_DB);
+    (db - MIN_DB) / (MAX_DB - MIN_DB) * width
+}
+
+impl View for LevelMeter {
+    fn element(&self) -> Option<&'static str> {
+        Some("level-meter")
+    }
+
+    fn event(&mut self, cx: &mut EventContext, event: &mut Event) {
+        event.map(|window_event, meta| match window_event {
+            WindowEvent::MouseDown(MouseButton::Left) if self.source == MeterSource::Output => {
+                self.ui_data.output_clipped.store(false, Ordering::Relaxed);
+                cx.needs_redraw();
+                meta.consume();
+            },
+            _ => {},
+        });
+
+        // The levels change all the time, redraw whenever there are new ones
+        event.map(|_: &FrameEvent, meta| {
+            cx.needs_redraw();
+            meta.consume();
+        });
+    }
+
+    fn draw(&self, cx: &mut DrawContext, canvas: &mut Canvas) {
+        let bounds = cx.bounds();
+        if bounds.w == 0.0 || bounds.h == 0.0 {
+            return;
+        }
+
+        let (levels, clipped) = match self.source {
+            MeterSource::Input  => (&self.ui_data.input_levels, None),
+            MeterSource::Drive  => (&self.ui_data.drive_levels, None),
+            MeterSource::Output => (
+                &self.ui_data.output_levels,
+                Some(self.ui_data.output_clipped.load(Ordering::Relaxed)),
+            ),
+        };
+        let (peak, rms) = levels.get();
+
+        // Follow the peak up right away and back down after the hold time
+        let now = Instant::now();
+        let previous = self.hold.get();
+        let hold: [(f32, Instant); 2] = std::array::from_fn(|i| {
+            let (held, time) = previous[i];
+            if peak[i] >= held || now.duration_since(time) > HOLD_TIME {
+                (peak[i], now)
+            } else {
+                (held, time)
+            }
+        });
+        self.hold.set(hold);
+
+        // Draw relative to the view
+        canvas.save();
+        canvas.translate(bounds.x, bounds.y);
+
+        let line_width = cx.style.dpi_factor as f32 * 1.5;
+        let border_color: vg::Color = cx.border_color().cloned().unwrap_or_default().into();
+        let font_color: vg::Color = cx.font_color().cloned().unwrap_or_default().into();
+
+        // Draw background color
+        let mut path = vg::Path::new();
+        let paint = vg::Paint::color(cx.background_color().cloned().unwrap_or_default().into());
+        path.rect(0.0, 0.0, bounds.w, bounds.h);
+        canvas.fill_path(&mut path, &paint);
+
+        // Leave a square for the clip LED on the output meter
+        let led = if clipped.is_some() { bounds.h } else { 0.0 };
+        let width = bounds.w - led;
+        let row = bounds.h * 0.5;
+        let gap = row * 0.2;
+
+        for i in 0..2 {
+            let y = i as f32 * row + gap * 0.5;
+            let h = row - gap;
+
+            let mut path = vg::Path::new();
+            path.rect(0.0, y, db_to_x(peak[i], width), h);
+            canvas.fill_path(&mut path, &vg::Paint::color(border_color));
+
+            let mut path = vg::Path::new();
+            path.rect(0.0, y, db_to_x(rms[i], width), h);
+            canvas.fill_path(&mut path, &vg::Paint::color(font_color));
+
+            let mut path = vg::Path::new();
+            let x = db_to_x(hold[i].0, width);
+            path.move_to(x, y);
+            path.line_to(x, y + h);
+            canvas.stroke_path(&mut path, &vg::Paint::color(font_color).with_line_width(line_width));
+        }
+
+        // Draw the 0 dB line
+        let mut path = vg::Path::new();
+        let zero = db_to_x(1.0, width);
+        path.move_to(zero, 0.0);
+        path.line_to(zero, bounds.h);
+        canvas.stroke_path(&mut path, &vg::Paint::color(border_color).with_line_width(line_width * 0.5));
+
+        if let Some(clipped) = clipped {
+            let mut path = vg::Path::new();
+            path.rect(width + gap, gap, led - gap * 2.0, bounds.h - gap * 2.0);
+            let color = if clipped { font_color } else { border_color };
+            canvas.fill_path(&mut path, &vg::Paint::color(color));
+        }
+
+        canvas.restore();
+    }
+}
@@ -0,0 +1,101 @@
+use crate::data::{UIData, WAVEFORM_LEN};
+use super::FrameEvent;
+use std::{cell::RefCell, sync::Arc};
+use nih_plug_vizia::vizia::{prelude::*, vg};
+
+/// Number of samples shown at once
+const DISPLAY_LEN: usize = 1024;
+
+pub struct Oscilloscope {
+    /// Reference to the UI data, which holds the waveforms
+    ui_data: Arc<UIData>,
+    /// Input and output waveforms read from the UI data, kept between draws so they
+    /// don't get allocated every frame
+    waveforms: RefCell<[Vec<f32>; 2]>,
+}
+
+impl Oscilloscope {
+    /// Create a new oscilloscope
+    pub fn new<LUIData>(
+        cx: &mut Context,
+        ui_data: LUIData,
+    ) -> Handle<Self> where
+        LUIData: Lens<Target = Arc<UIData>>,
+    {
+        Self {
+            ui_data: ui_data.get(cx),
+            waveforms: RefCell::new([vec![0.0; WAVEFORM_LEN], vec![0.0; WAVEFORM_LEN]]),
+        }.build(cx, super::redraw_on_frame)
+    }
+}
+
+/// Find the start of the shown window, the last rising zero crossing of the input
+/// that still leaves enough samples after it. Falls back to the latest samples
+fn trigger(input: &[f32]) -> usize {
+    let latest = input.len() - DISPLAY_LEN;
+    (1..=latest)
+        .rev()
+        .find(|&i| input[i - 1] < 0.0 && input[i] >= 0.0)
+        .unwrap_or(latest)
+}
+
+impl View for Oscilloscope {
+    fn element(&self) -> Option<&'static str> {
+        Some("oscilloscope")
+    }
+
+    fn event(&mut self, cx: &mut EventContext, event: &mut Event) {
+        // The waveforms change all the time, redraw whenever there are new ones
+        event.map(|_: &FrameEvent, meta| {
+            cx.needs_redraw();
+            meta.consume();
+        });
+    }
+
+    fn draw(&self, cx: &mut DrawContext, canvas: &mut Canvas) {
+        let bounds = cx.bounds();
+        if bounds.w == 0.0 || bounds.h == 0.0 {
+            return;
+        }
+
+        let mut waveforms = self.waveforms.borrow_mut();
+        let [input, output] = &mut *waveforms;
+        self.ui_data.waveform.read(input, output);
+        let start = trigger(input);
+
+        let line_width = cx.style.dpi_factor as f32 * 1.5;
+        // 1 <= scale <= 2;
+        let scale = 1.5f32;
+        let a = bounds.h * 0.5;
+
+        // Draw background color
+        let mut path = vg::Path::new();
+        let paint = vg::Paint::color(cx.background_color().cloned().unwrap_or_default().into());
+        path.rect(bounds.x, bounds.y, bounds.w, bounds.h);
+        canvas.fill_path(&mut path, &paint);
+
+        // Draw a trace, positive values go up
+        let trace = |samples: &[f32]| {
+            let mut path = vg::Path::new();
+            for (i, s) in samples[start..start + DISPLAY_LEN].iter().enumerate() {
+                let x = bounds.x + i as f32 / (DISPLAY_LEN - 1) as f32 * bounds.w;
+                let y = bounds.y + a - s.clamp(-scale, scale) * a * scale.recip();
+                if i == 0 {
+                    path.move_to(x, y);
+                } else {
+                    path.line_to(x, y);
+                }
+            }
+            path
+        };
+
+        // Input in the background, output on top
+        let paint = vg::Paint::color(cx.border_color().cloned().unwrap_or_default().into())
+            .with_line_width(line_width);
+        canvas.stroke_path(&mut trace(input), &paint);
+
+        let paint = vg::Paint::color(cx.font_color().cloned().unwrap_or_default().into())
+            .with_line_width(line_width);
+        canvas.stroke_path(&mut trace(output), &paint);
+    }
+}
@@ -0,0 +1,94 @@
+use crate::{PenareParams, fxs::graph::{NodeKind, Route}};
+use super::{Data, PatchEvent};
+use std::sync::Arc;
+use nih_plug_vizia::vizia::prelude::*;
+
+/// How much the level buttons of a parallel branch change its level
+const LEVEL_STEP: f32 = 0.1;
+
+/// Editor for the routing graph. Series routes are laid out from left to right and
+/// parallel routes from top to bottom with the level of each branch, clicking a node
+/// selects where the next node gets added
+pub struct PatchView;
+
+impl PatchView {
+    pub fn new<LParams>(
+        cx: &mut Context,
+        params: LParams,
+    ) -> Handle<Self> where
+        LParams: Lens<Target = Arc<PenareParams>>,
+    {
+        let params = params.get(cx);
+        Self.build(cx, |cx| {
+            HStack::new(cx, |cx| {
+                Button::new(cx, |cx| cx.emit(PatchEvent::PreviousKind), |cx| Label::new(cx, "<"));
+                Label::new(cx, Data::patch_kind.map(|kind| NodeKind::ALL[*kind].name()))
+                    .width(Stretch(1.0));
+                Button::new(cx, |cx| cx.emit(PatchEvent::NextKind), |cx| Label::new(cx, ">"));
+                Button::new(cx, |cx| cx.emit(PatchEvent::Insert(false)), |cx| Label::new(cx, "series"));
+                Button::new(cx, |cx| cx.emit(PatchEvent::Insert(true)), |cx| Label::new(cx, "parallel"));
+                Button::new(cx, |cx| cx.emit(PatchEvent::Remove), |cx| Label::new(cx, "remove"));
+            })
+            .height(Pixels(30.0))
+            .col_between(Pixels(4.0));
+
+            // Rebuild the graph whenever it's edited
+            Binding::new(cx, Data::patch_version, move |cx, _| {
+                let route = params.patch.read().map(|route| route.clone()).unwrap_or_default();
+                let selected = Data::patch_selected.get(cx);
+                build_route(cx, &route, &mut Vec::new(), &selected);
+            });
+        })
+    }
+}
+
+fn build_route(cx: &mut Context, route: &Route, path: &mut Vec<usize>, selected: &[usize]) {
+    match route {
+        Route::Node(kind) => {
+            let name = kind.name();
+            let node_path = path.clone();
+            Button::new(
+                cx,
+                move |cx| cx.emit(PatchEvent::Select(node_path.clone())),
+                move |cx| Label::new(cx, &name),
+            )
+            .class("patch-node")
+            .checked(path.as_slice() == selected);
+        },
+        Route::Series(routes) => {
+            HStack::new(cx, |cx| {
+                for (i, route) in routes.iter().enumerate() {
+                    path.push(i);
+                    build_route(cx, route, path, selected);
+                    path.pop();
+                }
+            })
+            .class("patch-series");
+        },
+        Route::Parallel(routes, levels) => {
+            VStack::new(cx, |cx| {
+                for (i, route) in routes.iter().enumerate() {
+                    path.push(i);
+                    // Every branch has its own level before the mixer
+                    let level = levels.get(i).copied().unwrap_or(1.0);
+                    HStack::new(cx, |cx| {
+                        build_route(cx, route, path, selected);
+                        let (down, up) = (path.clone(), path.clone());
+                        Button::new(cx, move |cx| cx.emit(PatchEvent::StepLevel(down.clone(), -LEVEL_STEP)), |cx| Label::new(cx, "-"));
+                        Label::new(cx, &format!("{:.0}%", level * 100.0)).class("patch-level");
+                        Button::new(cx, move |cx| cx.emit(PatchEvent::StepLevel(up.clone(), LEVEL_STEP)), |cx| Label::new(cx, "+"));
+                    })
+                    .class("patch-branch");
+                    path.pop();
+                }
+            })
+            .class("patch-parallel");
+        },
+    }
+}
+
+impl View for PatchView {
+    fn element(&self) -> Option<&'static str> {
+        Some("patch-view")
+    }
+}
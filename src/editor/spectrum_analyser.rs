@@ -0,0 +1,211 @@
+use crate::{
+    PenareParams,
+    data::{UIData, WAVEFORM_LEN},
+    fxs::spectrum::Analyser,
+};
+use super::FrameEvent;
+use std::{sync::Arc, time::{Duration, Instant}};
+use nih_plug::prelude::*;
+use nih_plug_vizia::vizia::{prelude::*, vg};
+
+/// Frequency range shown on the X axis
+const MIN_FREQ: f32 = 20.0;
+const MAX_FREQ: f32 = 22000.0;
+/// Level range shown on the Y axis, in dB
+const MIN_DB: f32 = -90.0;
+const MAX_DB: f32 = 6.0;
+/// How many times per second the spectrum is analysed, the averaging and the peak
+/// decay follow this rate no matter how often the editor redraws
+const UPDATE_RATE: f32 = 60.0;
+/// How fast the held peaks fall, in dB per second
+const PEAK_DECAY: f32 = 6.0;
+/// Frequencies of the grid lines
+const GRID: [f32; 3] = [100.0, 1000.0, 10000.0];
+
+/// Spectrum of the input and output overlaid on a log frequency axis, so the
+/// harmonics the waveshaper adds show up on top of the input. The held peaks fall
+/// slowly, click the view to clear them
+pub struct SpectrumAnalyser {
+    params: Arc<PenareParams>,
+    ui_data: Arc<UIData>,
+    /// Input and output waveforms read from the UI data, kept between updates so
+    /// they don't get allocated every time
+    waveforms: [Vec<f32>; 2],
+    /// Analysers of the input and output, kept between updates for the averaging
+    analysers: [Analyser; 2],
+    /// When the last update was due
+    last_update: Instant,
+}
+
+impl SpectrumAnalyser {
+    pub fn new<LParams, LUIData>(
+        cx: &mut Context,
+        params: LParams,
+        ui_data: LUIData,
+    ) -> Handle<Self> where
+        LParams: Lens<Target = Arc<PenareParams>>,
+        LUIData: Lens<Target = Arc<UIData>>,
+    {
+        Self {
+            params: params.get(cx),
+            ui_data: ui_data.get(cx),
+            waveforms: [vec![0.0; WAVEFORM_LEN], vec![0.0; WAVEFORM_LEN]],
+            analysers: Default::default(),
+            last_update: Instant::now(),
+        }.build(cx, super::redraw_on_frame)
+    }
+
+    /// Analyse the latest waveforms, catching up with every update that was due
+    /// since the last one. Returns false when no update was due yet
+    fn update(&mut self) -> bool {
+        let updates = (self.last_update.elapsed().as_secs_f32() * UPDATE_RATE) as i32;
+        if updates == 0 {
+            return false;
+        }
+        // Don't try to catch up after the editor stopped updating for a while
+        if updates as f32 > UPDATE_RATE {
+            self.last_update = Instant::now();
+        } else {
+            self.last_update += Duration::from_secs_f32(updates as f32 / UPDATE_RATE);
+        }
+        let updates = updates.min(UPDATE_RATE as i32);
+
+        let settings = self.params.analyser.read().map(|settings| *settings).unwrap_or_default();
+        let averaging = settings.averaging.coefficient(UPDATE_RATE).powi(updates);
+        let peak_decay = util::db_to_gain(-PEAK_DECAY * updates as f32 / UPDATE_RATE);
+
+        let [input, output] = &mut self.waveforms;
+        self.ui_data.waveform.read(input, output);
+        for (analyser, samples) in self.analysers.iter_mut().zip(&self.waveforms) {
+            analyser.process(samples, settings.resolution, averaging, peak_decay);
+        }
+        true
+    }
+}
+
+fn freq_to_x(freq: f32, width: f32) -> f32 {
+    (freq / MIN_FREQ).ln() / (MAX_FREQ / MIN_FREQ).ln() * width
+}
+
+fn x_to_freq(x: f32, width: f32) -> f32 {
+    MIN_FREQ * (MAX_FREQ / MIN_FREQ).powf(x / width)
+}
+
+fn level_to_y(level: f32, height: f32) -> f32 {
+    let db = util::gain_to_db(level).clamp(MIN_DB, MAX_DB);
+    (1.0 - (db - MIN_DB) / (MAX_DB - MIN_DB)) * height
+}
+
+/// Level of the spectrum between two frequencies: the loudest bin when there are
+/// several bins in between, otherwise interpolated between the nearest bins
+fn level_between(bins: &[f32], from: f32, to: f32) -> f32 {
+    let last = bins.len() - 1;
+    let (from, to) = (from.min(last as f32), to.min(last as f32));
+    if to - from >= 1.0 {
+        bins[from.ceil() as usize..=to.floor() as usize].iter().fold(0.0, |max: f32, &m| max.max(m))
+    } else {
+        let i = from.floor() as usize;
+        let t = from - i as f32;
+        bins[i] + (bins[(i + 1).min(last)] - bins[i]) * t
+    }
+}
+
+impl View for SpectrumAnalyser {
+    fn element(&self) -> Option<&'static str> {
+        Some("spectrum-analyser")
+    }
+
+    fn event(&mut self, cx: &mut EventContext, event: &mut Event) {
+        event.map(|window_event, meta| match window_event {
+            WindowEvent::MouseDown(MouseButton::Left) => {
+                for analyser in &mut self.analysers {
+                    analyser.reset_peaks();
+                }
+                cx.needs_redraw();
+                meta.consume();
+            },
+            _ => {},
+        });
+
+        // The spectrum changes all the time, analyse it whenever there's new audio
+        event.map(|_: &FrameEvent, meta| {
+            if self.update() {
+                cx.needs_redraw();
+            }
+            meta.consume();
+        });
+    }
+
+    fn draw(&self, cx: &mut DrawContext, canvas: &mut Canvas) {
+        let bounds = cx.bounds();
+        if bounds.w == 0.0 || bounds.h == 0.0 {
+            return;
+        }
+
+        let analysers = &self.analysers;
+        let peak_hold = self.params.analyser.read().map(|settings| settings.peak_hold).unwrap_or_default();
+
+        // Frequency of a bin
+        let bin_width = self.ui_data.get_sample_rate() / analysers[1].size().max(1) as f32;
+
+        // Draw relative to the view
+        canvas.save();
+        canvas.translate(bounds.x, bounds.y);
+
+        let line_width = cx.style.dpi_factor as f32 * 1.5;
+        let border_color: vg::Color = cx.border_color().cloned().unwrap_or_default().into();
+        let font_color: vg::Color = cx.font_color().cloned().unwrap_or_default().into();
+
+        // Draw background color
+        let mut path = vg::Path::new();
+        let paint = vg::Paint::color(cx.background_color().cloned().unwrap_or_default().into());
+        path.rect(0.0, 0.0, bounds.w, bounds.h);
+        canvas.fill_path(&mut path, &paint);
+
+        // Draw the grid
+        let mut path = vg::Path::new();
+        for freq in GRID {
+            let x = freq_to_x(freq, bounds.w);
+            path.move_to(x, 0.0);
+            path.line_to(x, bounds.h);
+        }
+        canvas.stroke_path(&mut path, &vg::Paint::color(border_color).with_line_width(line_width * 0.5));
+
+        // Draw a spectrum, one point per pixel
+        let trace = |bins: &[f32]| {
+            let mut path = vg::Path::new();
+            let steps = bounds.w.ceil() as usize;
+            for i in 0..=steps {
+                let x = i as f32;
+                let from = x_to_freq(x, bounds.w) / bin_width;
+                let to = x_to_freq(x + 1.0, bounds.w) / bin_width;
+                let y = level_to_y(level_between(bins, from, to), bounds.h);
+                if i == 0 {
+                    path.move_to(x, y);
+                } else {
+                    path.line_to(x, y);
+                }
+            }
+            path
+        };
+
+        // Nothing to draw before the first update
+        if analysers.iter().any(|analyser| analyser.magnitudes().is_empty()) {
+            canvas.restore();
+            return;
+        }
+
+        // Input in the background, output on top
+        let input_paint = vg::Paint::color(border_color).with_line_width(line_width);
+        let output_paint = vg::Paint::color(font_color).with_line_width(line_width);
+        if peak_hold {
+            let peak_width = line_width * 0.5;
+            canvas.stroke_path(&mut trace(analysers[0].peaks()), &input_paint.clone().with_line_width(peak_width));
+            canvas.stroke_path(&mut trace(analysers[1].peaks()), &output_paint.clone().with_line_width(peak_width));
+        }
+        canvas.stroke_path(&mut trace(analysers[0].magnitudes()), &input_paint);
+        canvas.stroke_path(&mut trace(analysers[1].magnitudes()), &output_paint);
+
+        canvas.restore();
+    }
+}
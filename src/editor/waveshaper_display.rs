@@ -1,27 +1,93 @@
-use crate::{fxs::utils::{hard_clip, mix_between}, data::UIData};
+use crate::{
+    PenareParams,
+    fxs::{
+        curve::Curve,
+        utils::{hard_clip, mix_between},
+        waveshaper::FunctionType,
+    },
+    data::UIData,
+};
 use std::{
     f32::consts::PI,
-    sync::{Arc, Mutex},
+    sync::Arc,
 };
 use nih_plug_vizia::vizia::{prelude::*, vg};
 
+// 1 <= scale <= 2;
+const SCALE: f32 = 1.5;
+/// Radius of the custom curve's points
+const POINT_RADIUS: f32 = 4.0;
+
 pub struct WaveshaperDisplay {
+    /// Reference to the parameters, which hold the custom curve
+    params: Arc<PenareParams>,
     /// Reference to the waveshapers data
-    ui_data: Arc<Mutex<UIData>>,
+    ui_data: Arc<UIData>,
+    /// Index of the custom curve point being dragged
+    dragging: Option<usize>,
+    /// Last mouse position relative to the view
+    mouse: (f32, f32),
 }
 
 impl WaveshaperDisplay {
     /// Create a new waveshaper display
-    pub fn new<LUIData>(
+    pub fn new<LParams, LUIData>(
         cx: &mut Context,
+        params: LParams,
         ui_data: LUIData,
     ) -> Handle<Self> where 
-        LUIData: Lens<Target = Arc<Mutex<UIData>>>,
+        LParams: Lens<Target = Arc<PenareParams>>,
+        LUIData: Lens<Target = Arc<UIData>>,
     {
         Self {
+            params: params.get(cx),
             ui_data: ui_data.get(cx),
+            dragging: None,
+            mouse: (0.0, 0.0),
         }.build(cx, |_cx| ())
     }
+
+    /// The custom curve can be edited when one of the functions uses it
+    fn editing(&self) -> bool {
+        self.params.pos_function_type.value() == FunctionType::Custom
+        || self.params.neg_function_type.value() == FunctionType::Custom
+    }
+
+    /// Change the custom curve and render it for the audio thread
+    fn edit_curve<T>(&self, f: impl FnOnce(&mut Curve) -> T) -> Option<T> {
+        let result = self.params.custom_curve.write().ok().map(|mut curve| f(&mut curve));
+        self.params.render_custom_curve();
+        result
+    }
+
+    /// Index of the curve point under the mouse
+    fn point_at(&self, bounds: BoundingBox) -> Option<usize> {
+        let curve = self.params.custom_curve.read().ok()?;
+        let (mx, my) = self.mouse;
+        curve.points
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let (x, y) = curve_to_view(p.x, p.y, bounds);
+                (i, (x - mx).hypot(y - my))
+            })
+            .filter(|(_, distance)| *distance <= POINT_RADIUS * 3.0)
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(i, _)| i)
+    }
+}
+
+/// Map a point of the curve to the view, the input on the X axis and the output on
+/// the Y axis
+fn curve_to_view(x: f32, y: f32, bounds: BoundingBox) -> (f32, f32) {
+    let a = bounds.h * 0.5;
+    ((x + 1.0) * 0.5 * bounds.w, a - y * a * SCALE.recip())
+}
+
+/// Inverse of [`curve_to_view`]
+fn view_to_curve(x: f32, y: f32, bounds: BoundingBox) -> (f32, f32) {
+    let a = bounds.h * 0.5;
+    (x / bounds.w * 2.0 - 1.0, (a - y) * SCALE / a)
 }
 
 impl View for WaveshaperDisplay {
@@ -29,6 +95,65 @@ impl View for WaveshaperDisplay {
         Some("waveshaper-display")
     }
 
+    fn event(&mut self, cx: &mut EventContext, event: &mut Event) {
+        event.map(|window_event, meta| match window_event {
+            // Drag a point
+            WindowEvent::MouseDown(MouseButton::Left) if self.editing() => {
+                if let Some(index) = self.point_at(cx.bounds()) {
+                    self.dragging = Some(index);
+                    cx.capture();
+                    meta.consume();
+                }
+            },
+            // Add a point
+            WindowEvent::MouseDoubleClick(MouseButton::Left) if self.editing() => {
+                let bounds = cx.bounds();
+                if self.point_at(bounds).is_none() {
+                    let (x, y) = view_to_curve(self.mouse.0, self.mouse.1, bounds);
+                    self.edit_curve(|curve| curve.insert(x, y));
+                    cx.needs_redraw();
+                    meta.consume();
+                }
+            },
+            // Remove a point
+            WindowEvent::MouseDown(MouseButton::Right) if self.editing() => {
+                if let Some(index) = self.point_at(cx.bounds()) {
+                    self.edit_curve(|curve| curve.remove(index));
+                    cx.needs_redraw();
+                    meta.consume();
+                }
+            },
+            WindowEvent::MouseUp(MouseButton::Left) => {
+                if self.dragging.take().is_some() {
+                    cx.release();
+                    meta.consume();
+                }
+            },
+            WindowEvent::MouseMove(x, y) => {
+                let bounds = cx.bounds();
+                self.mouse = (x - bounds.x, y - bounds.y);
+                if let Some(index) = self.dragging {
+                    let (x, y) = view_to_curve(self.mouse.0, self.mouse.1, bounds);
+                    self.edit_curve(|curve| curve.move_point(index, x, y));
+                    cx.needs_redraw();
+                }
+            },
+            // Bend the segment under the mouse
+            WindowEvent::MouseScroll(_, delta) if self.editing() => {
+                let (x, _) = view_to_curve(self.mouse.0, self.mouse.1, cx.bounds());
+                self.edit_curve(|curve| {
+                    let segment = curve.segment_at(x);
+                    if let Some(point) = curve.points.get_mut(segment) {
+                        point.curvature = (point.curvature + delta * 0.1).clamp(-1.0, 1.0);
+                    }
+                });
+                cx.needs_redraw();
+                meta.consume();
+            },
+            _ => {},
+        });
+    }
+
     fn draw(&self, cx: &mut DrawContext, canvas: &mut Canvas) {
         let bounds = cx.bounds();
         if bounds.w == 0.0 || bounds.h == 0.0 {
@@ -36,18 +161,22 @@ impl View for WaveshaperDisplay {
         }
 
         // Get UI data
-        let data = self.ui_data.lock().unwrap();
+        let data = &self.ui_data;
         let pos_function_type  = data.get_pos_function_type();
         let neg_function_type  = data.get_neg_function_type();
         let pos_function_param = data.get_pos_function_param();
         let neg_function_param = data.get_neg_function_param();
         let pos_function_mix   = data.get_pos_function_mix();
         let neg_function_mix   = data.get_neg_function_mix();
+        let bias               = data.get_bias();
+
+        // Draw relative to the view
+        canvas.save();
+        canvas.translate(bounds.x, bounds.y);
 
         // Calculate commonly used variables
         let line_width = cx.style.dpi_factor as f32 * 1.5;
-        // 1 <= scale <= 2;
-        let scale = 1.5f32;
+        let scale = SCALE;
         let a = bounds.h * 0.5;
 
         // Draw background color
@@ -77,19 +206,22 @@ impl View for WaveshaperDisplay {
             let x = x as f32;
             // Sin function
             let y_original = (-x * PI / (0.5 * bounds.w)).sin();
-            let y = y_original;
             // Apply function
-            let (ft, fp, fm) = if match (data.get_copy().is_on(), data.get_copy().is_positive(), -y >= 0.0) {
-                (true,  true,  _   ) => true,
-                (true,  false, _   ) => false,
-                (false, _,    true ) => true,
-                (false, _,    false) => false,
-            } {
-                (pos_function_type, pos_function_param, pos_function_mix)
-            } else {
-                (neg_function_type, neg_function_param, neg_function_mix)
+            let shape = |y: f32| {
+                let (ft, fp, fm) = if match (data.get_copy().is_on(), data.get_copy().is_positive(), -y >= 0.0) {
+                    (true,  true,  _   ) => true,
+                    (true,  false, _   ) => false,
+                    (false, _,    true ) => true,
+                    (false, _,    false) => false,
+                } {
+                    (pos_function_type, pos_function_param, pos_function_mix)
+                } else {
+                    (neg_function_type, neg_function_param, neg_function_mix)
+                };
+                mix_between(y, ft.apply_user(y, fp, &self.params.user_functions), fm)
             };
-            let y = mix_between(y, ft.apply(y, fp), fm);
+            // Bias
+            let y = if bias == 0.0 { shape(y_original) } else { shape(y_original + bias) - shape(bias) };
             // Clip sign
             let y = if data.get_clip_sign() {
                 if y_original >= 0.0 {
@@ -121,5 +253,35 @@ impl View for WaveshaperDisplay {
         }
 
         canvas.stroke_path(&mut path, &paint);
+
+        // Draw the custom curve and its points on top while it's in use
+        if self.editing() {
+            if let Ok(curve) = self.params.custom_curve.read() {
+                let border_color: vg::Color = cx.border_color().cloned().unwrap_or_default().into();
+                let font_color: vg::Color = cx.font_color().cloned().unwrap_or_default().into();
+
+                let mut path = vg::Path::new();
+                for x in 0..=(bounds.w as usize) {
+                    let x = x as f32 / bounds.w * 2.0 - 1.0;
+                    let (x, y) = curve_to_view(x, curve.evaluate(x), bounds);
+                    if x == 0.0 {
+                        path.move_to(x, y);
+                    } else {
+                        path.line_to(x, y);
+                    }
+                }
+                canvas.stroke_path(&mut path, &vg::Paint::color(border_color).with_line_width(line_width));
+
+                for (i, point) in curve.points.iter().enumerate() {
+                    let (x, y) = curve_to_view(point.x, point.y, bounds);
+                    let mut path = vg::Path::new();
+                    path.circle(x, y, POINT_RADIUS * cx.style.dpi_factor as f32);
+                    let color = if self.dragging == Some(i) { font_color } else { border_color };
+                    canvas.fill_path(&mut path, &vg::Paint::color(color));
+                }
+            }
+        }
+
+        canvas.restore();
     }
 }
\ No newline at end of file
@@ -0,0 +1,112 @@
+use crate::fxs::waveshaper::Shaper;
+use nih_plug::prelude::*;
+
+/// Below this difference between samples the ADAA formulas are ill-conditioned
+const TOLERANCE: f64 = 1e-5;
+
+#[derive(Clone, Copy, Debug, Enum, PartialEq)]
+pub enum Quality {
+    #[name = "None"]
+    None,
+    #[name = "ADAA1"]
+    Adaa1,
+    #[name = "ADAA2"]
+    Adaa2,
+}
+
+impl Quality {
+    /// Delay [`Adaa::process`] adds, in half samples. The ADAA output is the average
+    /// of the function over the last one (ADAA1) or two (ADAA2) sample intervals, so
+    /// it lags behind by half of that
+    pub fn half_sample_delay(&self) -> usize {
+        match self {
+            Quality::None  => 0,
+            Quality::Adaa1 => 1,
+            Quality::Adaa2 => 2,
+        }
+    }
+}
+
+/// Antiderivative anti-aliasing for the waveshaper, keeps the previous samples
+/// of a single channel
+#[derive(Clone, Copy, Default)]
+pub struct Adaa {
+    x1: f64,
+    x2: f64,
+}
+
+impl Adaa {
+    /// Apply the waveshaper to a sample with the given quality. The output is
+    /// delayed by [`Quality::half_sample_delay`], functions without closed-form
+    /// antiderivatives are applied as-is to the input delayed the same way
+    pub fn process(&mut self, x: f32, shaper: &Shaper, quality: Quality) -> f32 {
+        let x0 = x as f64;
+        let (y, delayed) = match quality {
+            Quality::None  => (None, x0),
+            Quality::Adaa1 => (self.adaa1(x0, shaper), (x0 + self.x1) * 0.5),
+            Quality::Adaa2 => (self.adaa2(x0, shaper), self.x1),
+        };
+        self.x2 = self.x1;
+        self.x1 = x0;
+
+        // The dry part of the function mix has to line up with the ADAA output
+        let delayed = delayed as f32;
+        match y {
+            Some(y) => shaper.finish(delayed, y as f32),
+            None    => shaper.apply(delayed),
+        }
+    }
+
+    fn adaa1(&self, x0: f64, shaper: &Shaper) -> Option<f64> {
+        let x1 = self.x1;
+        let dx = x0 - x1;
+        if dx.abs() < TOLERANCE {
+            // Fallback to the midpoint
+            let mid = (x0 + x1) * 0.5;
+            // Still check that the antiderivative exists so the result doesn't jump
+            // between ADAA and the plain function
+            shaper.antiderivative1(mid)?;
+            Some(shaper.shape(mid as f32) as f64)
+        } else {
+            Some((shaper.antiderivative1(x0)? - shaper.antiderivative1(x1)?) / dx)
+        }
+    }
+
+    fn adaa2(&self, x0: f64, shaper: &Shaper) -> Option<f64> {
+        let (x1, x2) = (self.x1, self.x2);
+
+        // First order divided difference of the second antiderivative
+        let d1 = |a: f64, b: f64| -> Option<f64> {
+            let d = a - b;
+            if d.abs() < TOLERANCE {
+                shaper.antiderivative1((a + b) * 0.5)
+            } else {
+                Some((shaper.antiderivative2(a)? - shaper.antiderivative2(b)?) / d)
+            }
+        };
+
+        let dx = x0 - x2;
+        if dx.abs() < TOLERANCE {
+            // Ill-conditioned, expand around the mean of x[n] and x[n - 2]
+            let x_bar = (x0 + x2) * 0.5;
+            let delta = x_bar - x1;
+            if delta.abs() < TOLERANCE {
+                let mid = (x_bar + x1) * 0.5;
+                shaper.antiderivative1(mid)?;
+                Some(shaper.shape(mid as f32) as f64)
+            } else {
+                Some(2.0 / delta * (
+                    shaper.antiderivative1(x_bar)?
+                    + (shaper.antiderivative2(x1)? - shaper.antiderivative2(x_bar)?) / delta
+                ))
+            }
+        } else {
+            Some(2.0 / dx * (d1(x0, x1)? - d1(x1, x2)?))
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.x1 = 0.0;
+        self.x2 = 0.0;
+    }
+}
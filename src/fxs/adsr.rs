@@ -0,0 +1,97 @@
+/// Stage of the envelope
+#[derive(Clone, Copy, PartialEq)]
+enum Stage {
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// Linear ADSR envelope triggered by MIDI notes, outputs values in [0, 1]
+#[derive(Clone, Copy)]
+pub struct Adsr {
+    stage: Stage,
+    value: f32,
+    /// Peak level of the current note (scaled by velocity)
+    peak: f32,
+    /// Held notes as a bitmask, the envelope only releases when every note is up
+    held: u128,
+}
+
+impl Default for Adsr {
+    fn default() -> Self {
+        Self {
+            stage: Stage::Idle,
+            value: 0.0,
+            peak: 1.0,
+            held: 0,
+        }
+    }
+}
+
+/// Per-sample step to cover `range` in `ms` milliseconds
+fn step(range: f32, ms: f32, sample_rate: f32) -> f32 {
+    range / (ms * 0.001 * sample_rate).max(1.0)
+}
+
+impl Adsr {
+    /// (Re)trigger the envelope from its current value. If it's above the new peak it
+    /// falls to it instead of jumping
+    pub fn note_on(&mut self, note: u8, peak: f32) {
+        self.held |= 1 << (note & 127);
+        self.peak = peak;
+        self.stage = Stage::Attack;
+    }
+
+    pub fn note_off(&mut self, note: u8) {
+        self.held &= !(1 << (note & 127));
+        if self.held == 0 && self.stage != Stage::Idle {
+            self.stage = Stage::Release;
+        }
+    }
+
+    /// Get the next value of the envelope. Times are in milliseconds and the
+    /// sustain level is relative to the peak
+    pub fn next(&mut self, attack: f32, decay: f32, sustain: f32, release: f32, sample_rate: f32) -> f32 {
+        let sustain = sustain * self.peak;
+        match self.stage {
+            Stage::Idle => self.value = 0.0,
+            // Retriggered with a lower peak, fall to it at the decay rate over the
+            // full range
+            Stage::Attack if self.value > self.peak => {
+                self.value = (self.value - step(1.0, decay, sample_rate)).max(self.peak);
+                if self.value <= self.peak {
+                    self.stage = Stage::Decay;
+                }
+            },
+            Stage::Attack => {
+                self.value += step(self.peak, attack, sample_rate);
+                if self.value >= self.peak {
+                    self.value = self.peak;
+                    self.stage = Stage::Decay;
+                }
+            },
+            Stage::Decay => {
+                self.value -= step(self.peak - sustain, decay, sample_rate);
+                if self.value <= sustain {
+                    self.value = sustain;
+                    self.stage = Stage::Sustain;
+                }
+            },
+            Stage::Sustain => self.value = sustain,
+            Stage::Release => {
+                self.value -= step(self.peak, release, sample_rate);
+                if self.value <= 0.0 {
+                    self.value = 0.0;
+                    self.stage = Stage::Idle;
+                }
+            },
+        }
+        self.value
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
@@ -0,0 +1,297 @@
+use nih_plug::prelude::*;
+use std::f32::consts::PI;
+
+/// Time constant of the loudness measurement in seconds
+const MEASURE_TIME: f32 = 3.0;
+/// Length of the audio the learn mode listens to, in seconds
+pub const LEARN_TIME: f32 = 5.0;
+/// Largest compensation in either direction, in dB
+const MAX_GAIN_DB: f32 = 24.0;
+/// Below this input power (about -70 dBFS) the compensation holds its last value
+const GATE: f32 = 1e-7;
+
+#[derive(Clone, Copy, Debug, Enum, PartialEq)]
+pub enum AutoGainMode {
+    Off,
+    /// Follow the loudness all the time
+    Continuous,
+    /// Use the gain found by the learn mode
+    Learned,
+}
+
+/// How loudness is measured
+#[derive(Clone, Copy, Debug, Enum, PartialEq)]
+pub enum Loudness {
+    #[name = "RMS"]
+    Rms,
+    /// ITU-R BS.1770 K-weighting, closer to how loud things sound
+    #[name = "K-Weighted"]
+    KWeighted,
+}
+
+/// Progress of the learn mode
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LearnState {
+    Idle,
+    Learning,
+    /// The last learn found a gain
+    Learned,
+    /// The input of the last learn was too quiet to find a gain
+    TooQuiet,
+}
+
+impl From<usize> for LearnState {
+    fn from(id: usize) -> Self {
+        match id {
+            1 => LearnState::Learning,
+            2 => LearnState::Learned,
+            3 => LearnState::TooQuiet,
+            _ => LearnState::Idle,
+        }
+    }
+}
+
+impl From<LearnState> for usize {
+    fn from(state: LearnState) -> Self {
+        state as usize
+    }
+}
+
+/// Second order section in transposed direct form II
+#[derive(Clone, Copy, Default)]
+struct Section {
+    b: [f32; 3],
+    a: [f32; 2],
+    s1: f32,
+    s2: f32,
+}
+
+impl Section {
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b[0] * x + self.s1;
+        self.s1 = self.b[1] * x - self.a[0] * y + self.s2;
+        self.s2 = self.b[2] * x - self.a[1] * y;
+        y
+    }
+
+    fn reset(&mut self) {
+        self.s1 = 0.0;
+        self.s2 = 0.0;
+    }
+}
+
+/// K-weighting filter: a high shelf for the head followed by a high-pass
+#[derive(Clone, Copy, Default)]
+struct KWeighting {
+    shelf: Section,
+    highpass: Section,
+}
+
+impl KWeighting {
+    /// Coefficients from BS.1770 redesigned for any sample rate
+    fn new(sample_rate: f32) -> Self {
+        let (f0, gain_db, q) = (1681.974_5, 3.999_844, 0.707_175_25);
+        let k = (PI * f0 / sample_rate).tan();
+        let vh = 10.0f32.powf(gain_db / 20.0);
+        let vb = vh.powf(0.499_666_77);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Section {
+            b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            ..Section::default()
+        };
+
+        let (f0, q) = (38.135_47, 0.500_327);
+        let k = (PI * f0 / sample_rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let highpass = Section {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            ..Section::default()
+        };
+        Self { shelf, highpass }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        self.highpass.process(self.shelf.process(x))
+    }
+
+    fn reset(&mut self) {
+        self.shelf.reset();
+        self.highpass.reset();
+    }
+}
+
+/// Loudness of a multichannel signal, as the slowly averaged power summed over the
+/// channels
+#[derive(Clone)]
+struct Meter {
+    weighting: Vec<KWeighting>,
+    power: f32,
+}
+
+impl Meter {
+    fn new(channels: usize, sample_rate: f32) -> Self {
+        Self {
+            weighting: vec![KWeighting::new(sample_rate); channels],
+            power: 0.0,
+        }
+    }
+
+    /// Power of a frame
+    fn frame_power(&mut self, frame: &[f32], loudness: Loudness) -> f32 {
+        frame.iter().zip(&mut self.weighting).map(|(x, weighting)| {
+            // Keep the filters running so switching modes doesn't glitch
+            let weighted = weighting.process(*x);
+            let x = match loudness {
+                Loudness::Rms       => *x,
+                Loudness::KWeighted => weighted,
+            };
+            x * x
+        }).sum()
+    }
+}
+
+/// Compares the loudness before and after the distortion and finds the gain that
+/// brings the output back to the input loudness
+#[derive(Clone)]
+pub struct AutoGain {
+    input: Meter,
+    output: Meter,
+    /// One-pole coefficient of the loudness measurement
+    coeff: f32,
+    /// Compensation gain of the continuous mode
+    gain: f32,
+    /// Power summed over the learn window so far
+    learn_input: f32,
+    learn_output: f32,
+    /// Samples left in the learn window, `None` when not learning
+    learn_left: Option<usize>,
+    learn_len: usize,
+    learn_state: LearnState,
+}
+
+impl AutoGain {
+    pub fn new(channels: usize, sample_rate: f32) -> Self {
+        Self {
+            input: Meter::new(channels, sample_rate),
+            output: Meter::new(channels, sample_rate),
+            coeff: (-1.0 / (MEASURE_TIME * sample_rate)).exp(),
+            gain: 1.0,
+            learn_input: 0.0,
+            learn_output: 0.0,
+            learn_left: None,
+            learn_len: (LEARN_TIME * sample_rate) as usize,
+            learn_state: LearnState::Idle,
+        }
+    }
+
+    /// Measure a frame before (`input`) and after (`output`) the distortion. The
+    /// output is measured without compensation
+    pub fn measure(&mut self, input: &[f32], output: &[f32], loudness: Loudness) {
+        let input_power = self.input.frame_power(input, loudness);
+        let output_power = self.output.frame_power(output, loudness);
+        self.input.power = input_power + self.coeff * (self.input.power - input_power);
+        self.output.power = output_power + self.coeff * (self.output.power - output_power);
+        if self.input.power > GATE {
+            self.gain = compensation(self.input.power, self.output.power);
+        }
+
+        if let Some(left) = &mut self.learn_left {
+            self.learn_input += input_power;
+            self.learn_output += output_power;
+            *left = left.saturating_sub(1);
+        }
+    }
+
+    /// Compensation gain of the continuous mode
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// Start listening for the learn mode
+    pub fn start_learning(&mut self) {
+        self.learn_input = 0.0;
+        self.learn_output = 0.0;
+        self.learn_left = Some(self.learn_len);
+        self.learn_state = LearnState::Learning;
+    }
+
+    /// The learned gain once the learn window is over. Learning stops after that,
+    /// [`AutoGain::learn_state`] tells whether the input was too quiet to find a gain
+    pub fn learned(&mut self) -> Option<f32> {
+        if self.learn_left != Some(0) {
+            return None;
+        }
+        self.learn_left = None;
+        let samples = self.learn_len.max(1) as f32;
+        if self.learn_input / samples > GATE {
+            self.learn_state = LearnState::Learned;
+            Some(compensation(self.learn_input, self.learn_output))
+        } else {
+            self.learn_state = LearnState::TooQuiet;
+            None
+        }
+    }
+
+    pub fn is_learning(&self) -> bool {
+        self.learn_left.is_some()
+    }
+
+    pub fn learn_state(&self) -> LearnState {
+        self.learn_state
+    }
+
+    pub fn reset(&mut self) {
+        for meter in [&mut self.input, &mut self.output] {
+            meter.power = 0.0;
+            meter.weighting.iter_mut().for_each(KWeighting::reset);
+        }
+        self.gain = 1.0;
+        self.learn_left = None;
+        self.learn_state = LearnState::Idle;
+    }
+}
+
+/// Gain that matches the output power to the input power
+fn compensation(input_power: f32, output_power: f32) -> f32 {
+    let max = util::db_to_gain(MAX_GAIN_DB);
+    (input_power / output_power.max(f32::MIN_POSITIVE)).sqrt().clamp(max.recip(), max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_loudness() {
+        for loudness in [Loudness::Rms, Loudness::KWeighted] {
+            let mut auto_gain = AutoGain::new(2, 48000.0);
+            auto_gain.start_learning();
+            let mut learned = None;
+            for i in 0..(LEARN_TIME * 48000.0) as usize + 1 {
+                let x = (i as f32 * 0.05).sin() * 0.5;
+                // A distortion that's 6 dB louder
+                auto_gain.measure(&[x, x], &[x * 2.0, x * 2.0], loudness);
+                learned = learned.or(auto_gain.learned());
+            }
+            assert!((auto_gain.gain() - 0.5).abs() < 0.01);
+            assert!((learned.unwrap() - 0.5).abs() < 0.01);
+            assert!(!auto_gain.is_learning());
+            assert_eq!(auto_gain.learn_state(), LearnState::Learned);
+        }
+    }
+
+    #[test]
+    fn too_quiet_to_learn() {
+        let mut auto_gain = AutoGain::new(1, 48000.0);
+        auto_gain.start_learning();
+        assert_eq!(auto_gain.learn_state(), LearnState::Learning);
+        for _ in 0..(LEARN_TIME * 48000.0) as usize + 1 {
+            auto_gain.measure(&[0.0], &[0.0], Loudness::Rms);
+            assert_eq!(auto_gain.learned(), None);
+        }
+        assert!(!auto_gain.is_learning());
+        assert_eq!(auto_gain.learn_state(), LearnState::TooQuiet);
+    }
+}
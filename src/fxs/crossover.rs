@@ -0,0 +1,139 @@
+use crate::fxs::filter::{Biquad, FilterType};
+use std::f32::consts::FRAC_1_SQRT_2;
+
+/// Maximum number of bands
+pub const MAX_BANDS: usize = 4;
+/// Number of crossover points needed for the maximum number of bands
+pub const MAX_SPLITS: usize = MAX_BANDS - 1;
+
+/// 4th order Linkwitz-Riley split (two cascaded Butterworth biquads per side).
+/// The low and high outputs sum to an allpass
+#[derive(Clone, Copy, Default)]
+struct LinkwitzRiley {
+    lp: [Biquad; 2],
+    hp: [Biquad; 2],
+}
+
+impl LinkwitzRiley {
+    fn set(&mut self, freq: f32, sample_rate: f32) {
+        for (filter, ty) in self.lp.iter_mut().map(|f| (f, FilterType::Lowpass))
+            .chain(self.hp.iter_mut().map(|f| (f, FilterType::Highpass)))
+        {
+            filter.filter_type = ty;
+            filter.freq = freq;
+            filter.q = FRAC_1_SQRT_2;
+            filter.sample_rate = sample_rate;
+            filter.calculate_coeff();
+        }
+    }
+
+    fn split(&mut self, x: f32) -> (f32, f32) {
+        let low = self.lp[0].process(x).0;
+        let low = self.lp[1].process(low).0;
+        let high = self.hp[0].process(x).0;
+        let high = self.hp[1].process(high).0;
+        (low, high)
+    }
+
+    /// Run the signal through the allpass with the same phase response as the split
+    fn allpass(&mut self, x: f32) -> f32 {
+        let (low, high) = self.split(x);
+        low + high
+    }
+
+    fn reset(&mut self) {
+        for filter in self.lp.iter_mut().chain(self.hp.iter_mut()) {
+            filter.reset();
+        }
+    }
+}
+
+/// Linkwitz-Riley crossover splitting a signal into up to [`MAX_BANDS`] bands that
+/// sum back with a flat magnitude response
+#[derive(Clone, Copy, Default)]
+pub struct Crossover {
+    splits: [LinkwitzRiley; MAX_SPLITS],
+    /// `compensation[band][split]` keeps the lower bands in phase with the bands
+    /// that went through the higher splits
+    compensation: [[LinkwitzRiley; MAX_SPLITS]; MAX_SPLITS],
+    freqs: [f32; MAX_SPLITS],
+}
+
+impl Crossover {
+    /// Set the crossover frequencies, they are sorted so the bands never overlap
+    pub fn set_frequencies(&mut self, mut freqs: [f32; MAX_SPLITS], sample_rate: f32) {
+        for i in 1..MAX_SPLITS {
+            freqs[i] = freqs[i].max(freqs[i - 1]);
+        }
+        if freqs == self.freqs {
+            return;
+        }
+        self.freqs = freqs;
+        for (split, freq) in freqs.iter().enumerate() {
+            self.splits[split].set(*freq, sample_rate);
+            for band in &mut self.compensation {
+                band[split].set(*freq, sample_rate);
+            }
+        }
+    }
+
+    /// Split a sample into `bands` bands (2 to [`MAX_BANDS`]), lowest band first
+    pub fn process(&mut self, x: f32, bands: usize) -> [f32; MAX_BANDS] {
+        let bands = bands.clamp(2, MAX_BANDS);
+        let mut out = [0.0; MAX_BANDS];
+
+        let mut rest = x;
+        for split in 0..bands - 1 {
+            let (low, high) = self.splits[split].split(rest);
+            out[split] = low;
+            rest = high;
+        }
+        out[bands - 1] = rest;
+
+        for band in 0..bands - 2 {
+            for split in band + 1..bands - 1 {
+                out[band] = self.compensation[band][split].allpass(out[band]);
+            }
+        }
+        out
+    }
+
+    pub fn reset(&mut self) {
+        for split in &mut self.splits {
+            split.reset();
+        }
+        for band in &mut self.compensation {
+            for split in band {
+                split.reset();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    #[test]
+    fn sums_flat() {
+        let sample_rate = 48000.0;
+        for bands in 2..=MAX_BANDS {
+            let mut crossover = Crossover::default();
+            crossover.set_frequencies([200.0, 2000.0, 8000.0], sample_rate);
+            let impulse = (0..8192)
+                .map(|n| crossover.process(if n == 0 { 1.0 } else { 0.0 }, bands).iter().sum::<f32>())
+                .collect::<Vec<_>>();
+
+            // Magnitude of the summed impulse response around and between the splits
+            for freq in [30.0, 200.0, 700.0, 2000.0, 4000.0, 8000.0, 16000.0] {
+                let w = 2.0 * PI * freq / sample_rate;
+                let (re, im) = impulse.iter().enumerate().fold((0.0, 0.0), |(re, im), (n, x)| {
+                    (re + x * (w * n as f32).cos(), im - x * (w * n as f32).sin())
+                });
+                let db = 20.0 * (re * re + im * im).sqrt().log10();
+                assert!(db.abs() < 0.05, "{} bands: {} dB at {} Hz", bands, db, freq);
+            }
+        }
+    }
+}
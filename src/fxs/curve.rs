@@ -0,0 +1,300 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use atomic_float::AtomicF32;
+use serde::{Deserialize, Serialize};
+
+/// Number of entries of a rendered curve
+pub const TABLE_LEN: usize = 2048;
+
+/// A control point of a custom curve. Both coordinates are in [-1, 1]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CurvePoint {
+    pub x: f32,
+    pub y: f32,
+    /// Curvature of the segment to the next point in [-1, 1], 0 follows the smooth
+    /// spline, positive curvatures rise early and negative late
+    pub curvature: f32,
+}
+
+/// Transfer curve drawn by the user, a monotone cubic spline through control points
+/// sorted by their x coordinate. The first and last points always stay at x = -1
+/// and x = 1, curves loaded from a preset or the plugin state are repaired to keep it
+/// that way
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(from = "UncheckedCurve")]
+pub struct Curve {
+    pub points: Vec<CurvePoint>,
+}
+
+/// A curve as it was saved, which may be unsorted or miss its end points
+#[derive(Deserialize)]
+struct UncheckedCurve {
+    points: Vec<CurvePoint>,
+}
+
+impl From<UncheckedCurve> for Curve {
+    fn from(unchecked: UncheckedCurve) -> Self {
+        let mut curve = Self { points: unchecked.points };
+        curve.repair();
+        curve
+    }
+}
+
+impl Default for Curve {
+    fn default() -> Self {
+        Self {
+            points: vec![
+                CurvePoint { x: -1.0, y: -1.0, curvature: 0.0 },
+                CurvePoint { x: 1.0, y: 1.0, curvature: 0.0 },
+            ],
+        }
+    }
+}
+
+/// Cubic Hermite interpolation between `y0` and `y1` with the tangents `m0` and `m1`
+/// scaled to the segment width, `u` is in [0, 1]
+fn hermite(u: f32, y0: f32, y1: f32, m0: f32, m1: f32) -> f32 {
+    let u2 = u * u;
+    let u3 = u2 * u;
+    (2.0 * u3 - 3.0 * u2 + 1.0) * y0
+        + (u3 - 2.0 * u2 + u) * m0
+        + (-2.0 * u3 + 3.0 * u2) * y1
+        + (u3 - u2) * m1
+}
+
+impl Curve {
+    /// Drop invalid points, clamp the rest to [-1, 1], sort them and add the end
+    /// points if they are missing
+    pub fn repair(&mut self) {
+        self.points.retain(|p| p.x.is_finite() && p.y.is_finite() && p.curvature.is_finite());
+        for point in &mut self.points {
+            point.x = point.x.clamp(-1.0, 1.0);
+            point.y = point.y.clamp(-1.0, 1.0);
+            point.curvature = point.curvature.clamp(-1.0, 1.0);
+        }
+        self.points.sort_by(|a, b| a.x.total_cmp(&b.x));
+
+        let (Some(first), Some(last)) = (self.points.first().copied(), self.points.last().copied()) else {
+            *self = Self::default();
+            return;
+        };
+        if first.x > -1.0 {
+            self.points.insert(0, CurvePoint { x: -1.0, y: first.y, curvature: 0.0 });
+        }
+        if last.x < 1.0 || self.points.len() < 2 {
+            self.points.push(CurvePoint { x: 1.0, y: last.y, curvature: 0.0 });
+        }
+    }
+
+    /// Evaluate the curve, the end points are held outside [-1, 1]
+    pub fn evaluate(&self, x: f32) -> f32 {
+        let points = &self.points;
+        let (Some(first), Some(last)) = (points.first(), points.last()) else {
+            return x;
+        };
+        if x <= first.x {
+            return first.y;
+        }
+        if x >= last.x {
+            return last.y;
+        }
+        let i = self.segment_at(x);
+        let (a, b) = (points[i], points[i + 1]);
+        let width = b.x - a.x;
+        if width <= f32::EPSILON {
+            return b.y;
+        }
+
+        // The curvature steepens one end of the segment and flattens the other, the
+        // tangents stay between 0 and 3 times the slope so the segment never overshoots
+        let slope = self.slope(i);
+        let (mut m0, mut m1) = (self.tangent(i), self.tangent(i + 1));
+        let curvature = a.curvature.clamp(-1.0, 1.0);
+        if curvature > 0.0 {
+            m0 += (3.0 * slope - m0) * curvature;
+            m1 *= 1.0 - curvature;
+        } else {
+            m0 *= 1.0 + curvature;
+            m1 -= (3.0 * slope - m1) * curvature;
+        }
+        hermite((x - a.x) / width, a.y, b.y, m0 * width, m1 * width)
+    }
+
+    /// Slope of the straight line from point `i` to the next one
+    fn slope(&self, i: usize) -> f32 {
+        let (a, b) = (self.points[i], self.points[i + 1]);
+        let width = b.x - a.x;
+        if width <= f32::EPSILON { 0.0 } else { (b.y - a.y) / width }
+    }
+
+    /// Tangent of the spline at point `i`. It's flat where the curve changes direction
+    /// so the spline stays monotone between the points (Fritsch-Carlson)
+    fn tangent(&self, i: usize) -> f32 {
+        let last = self.points.len() - 1;
+        if i == 0 {
+            return self.slope(0);
+        }
+        if i == last {
+            return self.slope(last - 1);
+        }
+        let (d0, d1) = (self.slope(i - 1), self.slope(i));
+        if d0 * d1 <= 0.0 {
+            0.0
+        } else {
+            2.0 * d0 * d1 / (d0 + d1)
+        }
+    }
+
+    /// Index of the point starting the segment `x` falls in
+    pub fn segment_at(&self, x: f32) -> usize {
+        let after = self.points.iter().position(|p| p.x > x).unwrap_or(self.points.len());
+        after.saturating_sub(1).min(self.points.len().saturating_sub(2))
+    }
+
+    /// Add a point, returning its index
+    pub fn insert(&mut self, x: f32, y: f32) -> usize {
+        let index = self.segment_at(x) + 1;
+        let curvature = self.points[index - 1].curvature;
+        self.points.insert(index, CurvePoint {
+            x: x.clamp(-1.0, 1.0),
+            y: y.clamp(-1.0, 1.0),
+            curvature,
+        });
+        index
+    }
+
+    /// Remove a point, the end points can't be removed
+    pub fn remove(&mut self, index: usize) {
+        if index > 0 && index + 1 < self.points.len() {
+            self.points.remove(index);
+        }
+    }
+
+    /// Move a point, keeping it between its neighbours. The end points can only move
+    /// vertically
+    pub fn move_point(&mut self, index: usize, x: f32, y: f32) {
+        if index >= self.points.len() {
+            return;
+        }
+        let last = self.points.len() - 1;
+        let x = if index == 0 || index == last {
+            self.points[index].x
+        } else {
+            x.clamp(self.points[index - 1].x, self.points[index + 1].x)
+        };
+        let point = &mut self.points[index];
+        point.x = x;
+        point.y = y.clamp(-1.0, 1.0);
+    }
+}
+
+/// A curve rendered to a lookup table that can be read from the audio thread. Two
+/// tables are kept so a new curve is rendered into the unused one and then swapped
+/// in, without locking or allocating
+pub struct CurveTable {
+    tables: [Vec<AtomicF32>; 2],
+    /// Index of the table the audio thread reads from
+    active: AtomicUsize,
+}
+
+impl CurveTable {
+    pub fn new(curve: &Curve) -> Self {
+        let table = curve_table(curve);
+        Self {
+            tables: [
+                table.iter().map(|y| AtomicF32::new(*y)).collect(),
+                table.iter().map(|y| AtomicF32::new(*y)).collect(),
+            ],
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    /// Render a curve and swap it in. Not meant to be called from the audio thread
+    pub fn render(&self, curve: &Curve) {
+        let next = 1 - self.active.load(Ordering::Acquire);
+        for (entry, y) in self.tables[next].iter().zip(curve_table(curve)) {
+            entry.store(y, Ordering::Relaxed);
+        }
+        self.active.store(next, Ordering::Release);
+    }
+
+    /// Look up the curve with linear interpolation, the ends are held outside [-1, 1]
+    pub fn lookup(&self, x: f32) -> f32 {
+        let table = &self.tables[self.active.load(Ordering::Acquire)];
+        let pos = (x.clamp(-1.0, 1.0) + 1.0) * 0.5 * (TABLE_LEN - 1) as f32;
+        let i = (pos as usize).min(TABLE_LEN - 2);
+        let frac = pos - i as f32;
+        let a = table[i].load(Ordering::Relaxed);
+        let b = table[i + 1].load(Ordering::Relaxed);
+        a + (b - a) * frac
+    }
+}
+
+/// Sample a curve over [-1, 1]
+fn curve_table(curve: &Curve) -> Vec<f32> {
+    (0..TABLE_LEN)
+        .map(|i| curve.evaluate(i as f32 / (TABLE_LEN - 1) as f32 * 2.0 - 1.0))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes_through_points() {
+        let mut curve = Curve::default();
+        let index = curve.insert(0.2, 0.8);
+        curve.points[0].curvature = 0.7;
+        curve.points[index].curvature = -0.4;
+        let table = CurveTable::new(&curve);
+        for point in &curve.points {
+            assert!((curve.evaluate(point.x) - point.y).abs() < 1e-5);
+            assert!((table.lookup(point.x) - point.y).abs() < 1e-2);
+        }
+        assert_eq!(curve.evaluate(3.0), 1.0);
+        assert_eq!(curve.evaluate(-3.0), -1.0);
+    }
+
+    #[test]
+    fn stays_between_points() {
+        let mut curve = Curve::default();
+        curve.insert(-0.5, 0.6);
+        curve.insert(0.3, -0.2);
+        for curvature in [-1.0, -0.3, 0.0, 0.5, 1.0] {
+            for point in &mut curve.points {
+                point.curvature = curvature;
+            }
+            for segment in curve.points.windows(2) {
+                let (low, high) = (segment[0].y.min(segment[1].y), segment[0].y.max(segment[1].y));
+                for i in 0..=100 {
+                    let x = segment[0].x + (segment[1].x - segment[0].x) * i as f32 / 100.0;
+                    let y = curve.evaluate(x);
+                    assert!(y >= low - 1e-5 && y <= high + 1e-5, "{} at {} with curvature {}", y, x, curvature);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn repairs_loaded_points() {
+        for json in [
+            r#"{"points":[]}"#,
+            r#"{"points":[{"x":0.2,"y":0.5,"curvature":0.0}]}"#,
+            r#"{"points":[{"x":1.0,"y":0.5,"curvature":0.0}]}"#,
+            r#"{"points":[{"x":0.5,"y":3.0,"curvature":2.0},{"x":-0.5,"y":-0.5,"curvature":0.0}]}"#,
+        ] {
+            let mut curve: Curve = serde_json::from_str(json).unwrap();
+            assert!(curve.points.len() >= 2, "{}", json);
+            assert_eq!(curve.points.first().unwrap().x, -1.0);
+            assert_eq!(curve.points.last().unwrap().x, 1.0);
+            assert!(curve.points.windows(2).all(|p| p[0].x <= p[1].x), "{}", json);
+            assert!(curve.points.iter().all(|p| p.y.abs() <= 1.0 && p.curvature.abs() <= 1.0));
+
+            // Editing a repaired curve must not panic
+            let index = curve.insert(0.0, 0.0);
+            curve.move_point(index, 0.1, 0.1);
+            curve.move_point(curve.points.len() - 1, 1.0, 0.9);
+            curve.remove(index);
+        }
+    }
+}
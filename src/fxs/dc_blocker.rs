@@ -0,0 +1,56 @@
+use std::f32::consts::TAU;
+
+/// Cutoff of the DC blocker in Hz
+const CUTOFF: f32 = 10.0;
+/// Time constant of the DC offset measurement in seconds
+const MEASURE_TIME: f32 = 0.3;
+
+/// One-pole high-pass removing the DC offset asymmetric waveshaping leaves behind,
+/// it also keeps track of how much offset there is
+#[derive(Clone, Copy, Default)]
+pub struct DcBlocker {
+    x1: f32,
+    y1: f32,
+    /// Slowly averaged input
+    offset: f32,
+}
+
+impl DcBlocker {
+    /// Remove the DC offset of a sample
+    pub fn process(&mut self, x: f32, sample_rate: f32) -> f32 {
+        let r = 1.0 - TAU * CUTOFF / sample_rate;
+        let y = x - self.x1 + r * self.y1;
+        self.x1 = x;
+        self.y1 = y;
+        self.offset += (x - self.offset) / (MEASURE_TIME * sample_rate);
+        y
+    }
+
+    /// DC offset of the input
+    pub fn offset(&self) -> f32 {
+        self.offset
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_offset() {
+        let mut blocker = DcBlocker::default();
+        let mut sum = 0.0;
+        for i in 0..96000 {
+            let y = blocker.process(0.5 + (i as f32 * 0.1).sin() * 0.25, 48000.0);
+            if i >= 48000 {
+                sum += y;
+            }
+        }
+        assert!((blocker.offset() - 0.5).abs() < 0.01);
+        assert!((sum / 48000.0).abs() < 0.01);
+    }
+}
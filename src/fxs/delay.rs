@@ -0,0 +1,37 @@
+/// Ring buffer delay line with a fixed maximum length
+#[derive(Clone)]
+pub struct DelayLine {
+    buffer: Vec<f32>,
+    pos: usize,
+    delay: usize,
+}
+
+impl DelayLine {
+    /// Create a delay line that can delay up to `capacity` samples
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buffer: vec![0.0; capacity + 1],
+            pos: 0,
+            delay: 0,
+        }
+    }
+
+    /// Set the delay in samples, clamped to the capacity
+    pub fn set_delay(&mut self, delay: usize) {
+        self.delay = delay.min(self.buffer.len() - 1);
+    }
+
+    /// Push a sample in and get the delayed sample out
+    pub fn process(&mut self, x: f32) -> f32 {
+        let len = self.buffer.len();
+        self.buffer[self.pos] = x;
+        let out = self.buffer[(self.pos + len - self.delay) % len];
+        self.pos = (self.pos + 1) % len;
+        out
+    }
+
+    pub fn reset(&mut self) {
+        self.buffer.iter_mut().for_each(|s| *s = 0.0);
+        self.pos = 0;
+    }
+}
@@ -0,0 +1,58 @@
+use nih_plug::prelude::*;
+
+#[derive(Clone, Copy, Debug, Enum, PartialEq)]
+pub enum DetectorMode {
+    Peak,
+    #[name = "RMS"]
+    Rms,
+}
+
+/// Signal the envelope follower listens to
+#[derive(Clone, Copy, Debug, Enum, PartialEq)]
+pub enum EnvelopeSource {
+    Input,
+    Sidechain,
+}
+
+/// Attack/release envelope follower
+#[derive(Clone, Copy, Default)]
+pub struct EnvelopeFollower {
+    /// Current envelope, squared in RMS mode
+    env: f32,
+    attack_coeff: f32,
+    release_coeff: f32,
+}
+
+/// One-pole coefficient for a time constant in milliseconds
+fn coeff(ms: f32, sample_rate: f32) -> f32 {
+    if ms <= 0.0 {
+        0.0
+    } else {
+        (-1.0 / (ms * 0.001 * sample_rate)).exp()
+    }
+}
+
+impl EnvelopeFollower {
+    pub fn set_times(&mut self, attack_ms: f32, release_ms: f32, sample_rate: f32) {
+        self.attack_coeff = coeff(attack_ms, sample_rate);
+        self.release_coeff = coeff(release_ms, sample_rate);
+    }
+
+    /// Follow a level (peak or RMS of the current frame), returns the envelope
+    pub fn process(&mut self, level: f32, mode: DetectorMode) -> f32 {
+        let input = match mode {
+            DetectorMode::Peak => level.abs(),
+            DetectorMode::Rms  => level * level,
+        };
+        let coeff = if input > self.env { self.attack_coeff } else { self.release_coeff };
+        self.env = input + coeff * (self.env - input);
+        match mode {
+            DetectorMode::Peak => self.env,
+            DetectorMode::Rms  => self.env.sqrt(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.env = 0.0;
+    }
+}
@@ -0,0 +1,435 @@
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use atomic_float::AtomicF32;
+
+/// Expression used until the user types their own
+pub const DEFAULT_EXPRESSION: &str = "sign(x) * (1 - exp(-abs(x) * t))";
+
+/// Maximum number of instructions of a compiled expression
+pub const MAX_OPS: usize = 256;
+/// Maximum depth of the evaluation stack
+const MAX_STACK: usize = 32;
+/// Maximum nesting of parentheses, function calls, negations and powers, so the
+/// parser doesn't overflow its stack on pasted garbage
+const MAX_NESTING: usize = 64;
+
+/// Instructions of the stack machine, operands are popped and the result pushed
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u32)]
+pub enum Op {
+    Const,
+    X,
+    T,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+    Neg,
+    Min,
+    Max,
+    Sin,
+    Cos,
+    Tan,
+    Tanh,
+    Atan,
+    Abs,
+    Sign,
+    Sqrt,
+    Exp,
+    Ln,
+    Floor,
+    Round,
+}
+
+impl Op {
+    const ALL: [Op; 23] = [
+        Op::Const, Op::X, Op::T, Op::Add, Op::Sub, Op::Mul, Op::Div, Op::Pow, Op::Neg,
+        Op::Min, Op::Max, Op::Sin, Op::Cos, Op::Tan, Op::Tanh, Op::Atan, Op::Abs,
+        Op::Sign, Op::Sqrt, Op::Exp, Op::Ln, Op::Floor, Op::Round,
+    ];
+
+    /// Function called by name, with its number of arguments
+    fn function(name: &str) -> Option<(Op, usize)> {
+        Some(match name {
+            "sin"   => (Op::Sin, 1),
+            "cos"   => (Op::Cos, 1),
+            "tan"   => (Op::Tan, 1),
+            "tanh"  => (Op::Tanh, 1),
+            "atan"  => (Op::Atan, 1),
+            "abs"   => (Op::Abs, 1),
+            "sign"  => (Op::Sign, 1),
+            "sqrt"  => (Op::Sqrt, 1),
+            "exp"   => (Op::Exp, 1),
+            "ln"    => (Op::Ln, 1),
+            "floor" => (Op::Floor, 1),
+            "round" => (Op::Round, 1),
+            "min"   => (Op::Min, 2),
+            "max"   => (Op::Max, 2),
+            "pow"   => (Op::Pow, 2),
+            _ => return None,
+        })
+    }
+
+    /// Change of the stack depth after running the instruction
+    fn stack_change(&self) -> isize {
+        match self {
+            Op::Const | Op::X | Op::T => 1,
+            Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Pow | Op::Min | Op::Max => -1,
+            _ => 0,
+        }
+    }
+}
+
+/// An instruction with its constant, only used by [`Op::Const`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Instruction {
+    pub op: Op,
+    pub value: f32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    /// Character the error was found at
+    pub position: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (at {})", self.message, self.position + 1)
+    }
+}
+
+/// An expression compiled to stack machine instructions
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+}
+
+impl Program {
+    /// Compile an expression in `x` and `t`, like `sign(x) * (1 - exp(-abs(x) * t))`
+    pub fn compile(source: &str) -> Result<Self, ParseError> {
+        let mut parser = Parser {
+            chars: source.chars().collect(),
+            pos: 0,
+            depth: 0,
+            program: Program::default(),
+        };
+        parser.expression()?;
+        parser.skip_whitespace();
+        if parser.pos < parser.chars.len() {
+            return Err(parser.error("Unexpected character"));
+        }
+        let program = parser.program;
+
+        if program.instructions.len() > MAX_OPS {
+            return Err(ParseError { position: 0, message: String::from("Expression is too long") });
+        }
+        let mut depth = 0;
+        for instruction in &program.instructions {
+            depth += instruction.op.stack_change();
+            if depth as usize > MAX_STACK {
+                return Err(ParseError { position: 0, message: String::from("Expression is nested too deep") });
+            }
+        }
+        Ok(program)
+    }
+
+    /// Evaluate the expression
+    pub fn eval(&self, x: f32, t: f32) -> f32 {
+        run(self.instructions.iter().copied(), x, t)
+    }
+}
+
+/// Run instructions on a fixed size stack. Malformed programs don't panic, missing
+/// operands are read as 0 and results that aren't finite become 0
+fn run(instructions: impl Iterator<Item = Instruction>, x: f32, t: f32) -> f32 {
+    let mut stack = [0.0f32; MAX_STACK];
+    let mut len = 0usize;
+    for Instruction { op, value } in instructions {
+        let (a, b) = match op.stack_change() {
+            -1 => {
+                len = len.saturating_sub(2);
+                (stack[len], stack[len + 1])
+            },
+            0 => {
+                len = len.saturating_sub(1);
+                (stack[len], 0.0)
+            },
+            _ => (0.0, 0.0),
+        };
+        let result = match op {
+            Op::Const => value,
+            Op::X     => x,
+            Op::T     => t,
+            Op::Add   => a + b,
+            Op::Sub   => a - b,
+            Op::Mul   => a * b,
+            Op::Div   => a / b,
+            Op::Pow   => a.powf(b),
+            Op::Neg   => -a,
+            Op::Min   => a.min(b),
+            Op::Max   => a.max(b),
+            Op::Sin   => a.sin(),
+            Op::Cos   => a.cos(),
+            Op::Tan   => a.tan(),
+            Op::Tanh  => a.tanh(),
+            Op::Atan  => a.atan(),
+            Op::Abs   => a.abs(),
+            Op::Sign  => if a == 0.0 { 0.0 } else { a.signum() },
+            Op::Sqrt  => a.sqrt(),
+            Op::Exp   => a.exp(),
+            Op::Ln    => a.ln(),
+            Op::Floor => a.floor(),
+            Op::Round => a.round(),
+        };
+        if len < MAX_STACK {
+            stack[len] = result;
+            len += 1;
+        }
+    }
+    let result = if len > 0 { stack[len - 1] } else { 0.0 };
+    if result.is_finite() { result } else { 0.0 }
+}
+
+/// Recursive descent parser emitting the instructions in postfix order
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    /// How deep the rule being parsed is nested
+    depth: usize,
+    program: Program,
+}
+
+impl Parser {
+    fn error(&self, message: &str) -> ParseError {
+        ParseError { position: self.pos, message: String::from(message) }
+    }
+
+    fn emit(&mut self, op: Op) {
+        self.program.instructions.push(Instruction { op, value: 0.0 });
+    }
+
+    /// Parse a nested rule, failing once the nesting gets too deep
+    fn nested(&mut self, rule: fn(&mut Self) -> Result<(), ParseError>) -> Result<(), ParseError> {
+        if self.depth >= MAX_NESTING {
+            return Err(self.error("Expression is nested too deep"));
+        }
+        self.depth += 1;
+        let result = rule(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    /// Skip whitespace and consume `c` if it's next
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.chars.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    // expression = term { ("+" | "-") term }
+    fn expression(&mut self) -> Result<(), ParseError> {
+        self.term()?;
+        loop {
+            if self.eat('+') {
+                self.term()?;
+                self.emit(Op::Add);
+            } else if self.eat('-') {
+                self.term()?;
+                self.emit(Op::Sub);
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    // term = unary { ("*" | "/") unary }
+    fn term(&mut self) -> Result<(), ParseError> {
+        self.unary()?;
+        loop {
+            if self.eat('*') {
+                self.unary()?;
+                self.emit(Op::Mul);
+            } else if self.eat('/') {
+                self.unary()?;
+                self.emit(Op::Div);
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    // unary = "-" unary | power
+    fn unary(&mut self) -> Result<(), ParseError> {
+        if self.eat('-') {
+            self.nested(Self::unary)?;
+            self.emit(Op::Neg);
+            Ok(())
+        } else {
+            self.power()
+        }
+    }
+
+    // power = atom [ "^" unary ]
+    fn power(&mut self) -> Result<(), ParseError> {
+        self.atom()?;
+        if self.eat('^') {
+            self.nested(Self::unary)?;
+            self.emit(Op::Pow);
+        }
+        Ok(())
+    }
+
+    // atom = number | name | name "(" expression { "," expression } ")" | "(" expression ")"
+    fn atom(&mut self) -> Result<(), ParseError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        match self.chars.get(self.pos) {
+            Some(c) if c.is_ascii_digit() || *c == '.' => {
+                while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_digit() || *c == '.') {
+                    self.pos += 1;
+                }
+                let text: String = self.chars[start..self.pos].iter().collect();
+                let value = text.parse::<f32>().map_err(|_| ParseError {
+                    position: start,
+                    message: String::from("Invalid number"),
+                })?;
+                self.program.instructions.push(Instruction { op: Op::Const, value });
+                Ok(())
+            },
+            Some(c) if c.is_alphabetic() => {
+                while self.chars.get(self.pos).is_some_and(|c| c.is_alphanumeric()) {
+                    self.pos += 1;
+                }
+                let name: String = self.chars[start..self.pos].iter().collect();
+                match name.as_str() {
+                    "x"  => self.emit(Op::X),
+                    "t"  => self.emit(Op::T),
+                    "pi" => self.program.instructions.push(Instruction { op: Op::Const, value: std::f32::consts::PI }),
+                    "e"  => self.program.instructions.push(Instruction { op: Op::Const, value: std::f32::consts::E }),
+                    _ => {
+                        let Some((op, args)) = Op::function(&name) else {
+                            return Err(ParseError { position: start, message: format!("Unknown name \"{}\"", name) });
+                        };
+                        if !self.eat('(') {
+                            return Err(self.error("Expected \"(\""));
+                        }
+                        for i in 0..args {
+                            if i > 0 && !self.eat(',') {
+                                return Err(self.error(&format!("{} takes {} arguments", name, args)));
+                            }
+                            self.nested(Self::expression)?;
+                        }
+                        if !self.eat(')') {
+                            return Err(self.error("Expected \")\""));
+                        }
+                        self.emit(op);
+                    },
+                }
+                Ok(())
+            },
+            Some('(') => {
+                self.pos += 1;
+                self.nested(Self::expression)?;
+                if !self.eat(')') {
+                    return Err(self.error("Expected \")\""));
+                }
+                Ok(())
+            },
+            Some(_) => Err(self.error("Unexpected character")),
+            None => Err(self.error("Unexpected end of expression")),
+        }
+    }
+}
+
+/// A compiled expression that can be run from the audio thread. Like
+/// [`CurveTable`](crate::fxs::curve::CurveTable) two programs are kept so a new
+/// one is written to the unused one and then swapped in, without locking or
+/// allocating
+pub struct ExpressionSlot {
+    ops: [Vec<AtomicU32>; 2],
+    values: [Vec<AtomicF32>; 2],
+    lens: [AtomicUsize; 2],
+    /// Index of the program the audio thread runs
+    active: AtomicUsize,
+}
+
+impl ExpressionSlot {
+    pub fn new(program: &Program) -> Self {
+        let slot = Self {
+            ops: std::array::from_fn(|_| (0..MAX_OPS).map(|_| AtomicU32::new(0)).collect()),
+            values: std::array::from_fn(|_| (0..MAX_OPS).map(|_| AtomicF32::new(0.0)).collect()),
+            lens: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            active: AtomicUsize::new(0),
+        };
+        slot.load(program);
+        slot
+    }
+
+    /// Swap in a compiled program. Not meant to be called from the audio thread
+    pub fn load(&self, program: &Program) {
+        let next = 1 - self.active.load(Ordering::Acquire);
+        let instructions = &program.instructions[..program.instructions.len().min(MAX_OPS)];
+        for (i, instruction) in instructions.iter().enumerate() {
+            self.ops[next][i].store(instruction.op as u32, Ordering::Relaxed);
+            self.values[next][i].store(instruction.value, Ordering::Relaxed);
+        }
+        self.lens[next].store(instructions.len(), Ordering::Relaxed);
+        self.active.store(next, Ordering::Release);
+    }
+
+    /// Evaluate the current program
+    pub fn eval(&self, x: f32, t: f32) -> f32 {
+        let active = self.active.load(Ordering::Acquire);
+        let len = self.lens[active].load(Ordering::Relaxed).min(MAX_OPS);
+        let instructions = (0..len).map(|i| Instruction {
+            op: Op::ALL
+                .get(self.ops[active][i].load(Ordering::Relaxed) as usize)
+                .copied()
+                .unwrap_or(Op::Const),
+            value: self.values[active][i].load(Ordering::Relaxed),
+        });
+        run(instructions, x, t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compile() {
+        let program = Program::compile("sign(x) * (1 - exp(-abs(x) * t))").unwrap();
+        let slot = ExpressionSlot::new(&program);
+        for x in [-2.0f32, -0.3, 0.0, 0.5, 3.0] {
+            let expected = x.signum() * (1.0 - (-x.abs() * 2.0).exp()) * if x == 0.0 { 0.0 } else { 1.0 };
+            assert!((program.eval(x, 2.0) - expected).abs() < 1e-6);
+            assert!((slot.eval(x, 2.0) - expected).abs() < 1e-6);
+        }
+        assert_eq!(Program::compile("-2^2 + 3 * 4").unwrap().eval(0.0, 0.0), 8.0);
+        assert_eq!(Program::compile("max(x, t) / 2").unwrap().eval(1.0, 3.0), 1.5);
+
+        assert!(Program::compile("x +").is_err());
+        assert!(Program::compile("foo(x)").is_err());
+        assert!(Program::compile("min(x)").is_err());
+        assert!(Program::compile("(x").is_err());
+
+        // Deep nesting is an error instead of a stack overflow
+        let deep = "(".repeat(100_000) + "x" + &")".repeat(100_000);
+        assert!(Program::compile(&deep).is_err());
+        assert!(Program::compile(&("-".repeat(100_000) + "x")).is_err());
+        assert!(Program::compile(&"abs(".repeat(100_000)).is_err());
+        assert_eq!(Program::compile("((((-x))))").unwrap().eval(1.0, 0.0), -1.0);
+    }
+}
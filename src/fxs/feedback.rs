@@ -0,0 +1,67 @@
+use crate::fxs::delay::DelayLine;
+use std::f32::consts::TAU;
+
+/// Longest feedback delay in milliseconds
+pub const MAX_DELAY_MS: f32 = 50.0;
+/// Level the fed back signal is softly limited to
+const LIMIT: f32 = 1.0;
+
+/// Feedback loop around the distortion: the output goes through a delay and a damping
+/// lowpass before being added back to the input
+#[derive(Clone)]
+pub struct Feedback {
+    delay: DelayLine,
+    /// One-pole lowpass state
+    lowpass: f32,
+    /// Output of the distortion at the previous sample
+    last: f32,
+}
+
+impl Feedback {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            delay: DelayLine::new((MAX_DELAY_MS * 0.001 * sample_rate).ceil() as usize),
+            lowpass: 0.0,
+            last: 0.0,
+        }
+    }
+
+    /// Get the signal to feed back into the distortion for this sample. `delay` is in
+    /// samples and `damping` is the cutoff of the lowpass in Hz
+    pub fn next(&mut self, delay: usize, damping: f32, sample_rate: f32) -> f32 {
+        self.delay.set_delay(delay);
+        let x = self.delay.process(self.last);
+        let coeff = 1.0 - (-TAU * damping / sample_rate).exp();
+        self.lowpass += coeff * (x - self.lowpass);
+        self.lowpass
+    }
+
+    /// Store the output of the distortion for the next samples. It is softly limited
+    /// so the loop can't blow up, whatever the gain around it
+    pub fn push(&mut self, y: f32) {
+        self.last = if y.is_finite() { LIMIT * (y / LIMIT).tanh() } else { 0.0 };
+    }
+
+    pub fn reset(&mut self) {
+        self.delay.reset();
+        self.lowpass = 0.0;
+        self.last = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stays_bounded() {
+        let mut feedback = Feedback::new(48000.0);
+        for i in 0..48000 {
+            let x = if i == 0 { 1.0 } else { 0.0 };
+            let fb = feedback.next(48, 20000.0, 48000.0);
+            assert!(fb.abs() <= LIMIT);
+            // A runaway gain inside the loop
+            feedback.push((x + fb) * 100.0);
+        }
+    }
+}
@@ -93,6 +93,18 @@ impl Biquad {
         (output, x - output)
     }
 
+    /// Magnitude response of the filter at a frequency
+    pub fn magnitude(&self, freq: f32) -> f32 {
+        let w = 2.0 * PI * freq / self.sample_rate;
+        let (s1, c1) = w.sin_cos();
+        let (s2, c2) = (2.0 * w).sin_cos();
+        let num_re = self.a0 + self.a1 * c1 + self.a2 * c2;
+        let num_im = -(self.a1 * s1 + self.a2 * s2);
+        let den_re = 1.0 + self.b1 * c1 + self.b2 * c2;
+        let den_im = -(self.b1 * s1 + self.b2 * s2);
+        ((num_re * num_re + num_im * num_im) / (den_re * den_re + den_im * den_im)).sqrt()
+    }
+
     pub fn reset(&mut self) {
         self.s1 = 0.0;
         self.s2 = 0.0;
@@ -0,0 +1,522 @@
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of nodes in a graph
+pub const MAX_NODES: usize = 16;
+/// Maximum number of instructions of a compiled graph, every node can add at most
+/// a node, duplicate, level, swap and mix instruction
+const MAX_OPS: usize = 5 * MAX_NODES;
+/// Largest level of a parallel branch
+pub const MAX_LEVEL: f32 = 2.0;
+/// Maximum depth of the evaluation stack
+const MAX_STACK: usize = MAX_NODES + 1;
+
+/// A processing node, the nodes use the parameters of the matching fixed section
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum NodeKind {
+    /// The signal filter 1 lets through
+    Filter1,
+    /// The signal filter 2 lets through
+    Filter2,
+    /// The signal filter 1 takes out
+    Excess1,
+    /// The signal filter 2 takes out
+    Excess2,
+    /// A waveshaper stage, 0 is the main waveshaper
+    Shaper(usize),
+    PreGain,
+    PostGain,
+}
+
+impl NodeKind {
+    /// Every node that can be added to a graph
+    pub const ALL: [NodeKind; 10] = [
+        NodeKind::Filter1,
+        NodeKind::Filter2,
+        NodeKind::Excess1,
+        NodeKind::Excess2,
+        NodeKind::Shaper(0),
+        NodeKind::Shaper(1),
+        NodeKind::Shaper(2),
+        NodeKind::Shaper(3),
+        NodeKind::PreGain,
+        NodeKind::PostGain,
+    ];
+
+    pub fn name(&self) -> String {
+        match self {
+            NodeKind::Filter1    => String::from("filter 1"),
+            NodeKind::Filter2    => String::from("filter 2"),
+            NodeKind::Excess1    => String::from("excess 1"),
+            NodeKind::Excess2    => String::from("excess 2"),
+            NodeKind::Shaper(0)  => String::from("waveshaper"),
+            NodeKind::Shaper(i)  => format!("stage {}", i + 1),
+            NodeKind::PreGain    => String::from("pre gain"),
+            NodeKind::PostGain   => String::from("post gain"),
+        }
+    }
+}
+
+/// A series-parallel processing graph. Graphs loaded from a preset or the plugin state
+/// are repaired to only hold nodes this version knows and at most [`MAX_NODES`] of them
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(from = "UncheckedRoute")]
+pub enum Route {
+    Node(NodeKind),
+    /// Routes running one after the other
+    Series(Vec<Route>),
+    /// Routes running on the same input, averaged by a mixer at the end. The second
+    /// field holds the level of each branch, a missing level is 1
+    Parallel(Vec<Route>, Vec<f32>),
+}
+
+/// A graph as it was saved, which may hold unknown nodes, too many nodes or levels
+/// out of range
+#[derive(Deserialize)]
+enum UncheckedRoute {
+    Node(NodeKind),
+    Series(Vec<UncheckedRoute>),
+    Parallel(Vec<UncheckedRoute>, Vec<f32>),
+}
+
+impl UncheckedRoute {
+    /// Convert to a route, dropping unknown nodes and the nodes after the first
+    /// `budget` ones. Dropped nodes leave empty groups behind for `simplify`
+    fn repair(self, budget: &mut usize) -> Route {
+        match self {
+            UncheckedRoute::Node(kind) if *budget > 0 && NodeKind::ALL.contains(&kind) => {
+                *budget -= 1;
+                Route::Node(kind)
+            },
+            UncheckedRoute::Node(_) => Route::Series(vec![]),
+            UncheckedRoute::Series(routes) => {
+                Route::Series(routes.into_iter().map(|route| route.repair(budget)).collect())
+            },
+            UncheckedRoute::Parallel(routes, levels) => {
+                let levels = levels.into_iter()
+                    .map(|level| if level.is_finite() { level.clamp(0.0, MAX_LEVEL) } else { 1.0 })
+                    .collect();
+                Route::Parallel(routes.into_iter().map(|route| route.repair(budget)).collect(), levels)
+            },
+        }
+    }
+}
+
+impl From<UncheckedRoute> for Route {
+    fn from(unchecked: UncheckedRoute) -> Self {
+        let mut budget = MAX_NODES;
+        let mut route = unchecked.repair(&mut budget);
+        // The editor adds nodes to the whole graph in series
+        if !matches!(route, Route::Series(_)) {
+            route = Route::Series(vec![route]);
+        }
+        route.simplify();
+        route
+    }
+}
+
+impl Default for Route {
+    /// The same path as the fixed section
+    fn default() -> Self {
+        Route::Series(vec![
+            Route::Node(NodeKind::Filter1),
+            Route::Node(NodeKind::Filter2),
+            Route::Node(NodeKind::PreGain),
+            Route::Node(NodeKind::Shaper(0)),
+            Route::Node(NodeKind::PostGain),
+        ])
+    }
+}
+
+impl Route {
+    pub fn node_count(&self) -> usize {
+        match self {
+            Route::Node(_) => 1,
+            Route::Series(routes) | Route::Parallel(routes, _) => routes.iter().map(Route::node_count).sum(),
+        }
+    }
+
+    /// Largest number of waveshapers a signal goes through, in series
+    pub fn shaper_depth(&self) -> usize {
+        match self {
+            Route::Node(NodeKind::Shaper(_)) => 1,
+            Route::Node(_) => 0,
+            Route::Series(routes) => routes.iter().map(Route::shaper_depth).sum(),
+            Route::Parallel(routes, _) => routes.iter().map(Route::shaper_depth).max().unwrap_or(0),
+        }
+    }
+
+    /// The route at a path of child indices, the empty path is the whole graph
+    pub fn get(&self, path: &[usize]) -> Option<&Route> {
+        match (self, path.split_first()) {
+            (_, None) => Some(self),
+            (Route::Series(routes) | Route::Parallel(routes, _), Some((i, rest))) => routes.get(*i)?.get(rest),
+            (Route::Node(_), Some(_)) => None,
+        }
+    }
+
+    fn get_mut(&mut self, path: &[usize]) -> Option<&mut Route> {
+        match (self, path.split_first()) {
+            (route, None) => Some(route),
+            (Route::Series(routes) | Route::Parallel(routes, _), Some((i, rest))) => routes.get_mut(*i)?.get_mut(rest),
+            (Route::Node(_), Some(_)) => None,
+        }
+    }
+
+    /// Add a node after the route at `path`, in series or in parallel with it.
+    /// Returns the path of the new node, or `None` if the graph is full
+    pub fn insert(&mut self, path: &[usize], node: NodeKind, parallel: bool) -> Option<Vec<usize>> {
+        if self.node_count() >= MAX_NODES {
+            return None;
+        }
+        let group = |routes: Vec<Route>| if parallel {
+            let levels = vec![1.0; routes.len()];
+            Route::Parallel(routes, levels)
+        } else {
+            Route::Series(routes)
+        };
+
+        let Some((index, parent_path)) = path.split_last() else {
+            // The whole graph is selected
+            if parallel {
+                let graph = std::mem::replace(self, Route::Series(vec![]));
+                *self = Route::Series(vec![group(vec![graph, Route::Node(node)])]);
+                return Some(vec![0, 1]);
+            }
+            let Route::Series(routes) = self else { return None };
+            routes.push(Route::Node(node));
+            return Some(vec![routes.len() - 1]);
+        };
+
+        let parent = self.get_mut(parent_path)?;
+        let same_group = matches!(
+            (&*parent, parallel),
+            (Route::Parallel(..), true) | (Route::Series(_), false)
+        );
+        if let Route::Parallel(routes, levels) = parent {
+            levels.resize(routes.len(), 1.0);
+            if same_group {
+                levels.insert(index + 1, 1.0);
+            }
+        }
+        let (Route::Series(routes) | Route::Parallel(routes, _)) = parent else { return None };
+        let mut path = path.to_vec();
+        if same_group {
+            routes.insert(index + 1, Route::Node(node));
+            *path.last_mut()? += 1;
+        } else {
+            let route = routes.get_mut(*index)?;
+            let old = std::mem::replace(route, Route::Series(vec![]));
+            *route = group(vec![old, Route::Node(node)]);
+            path.push(1);
+        }
+        Some(path)
+    }
+
+    /// Remove the route at `path`
+    pub fn remove(&mut self, path: &[usize]) {
+        let Some((index, parent_path)) = path.split_last() else {
+            *self = Route::Series(vec![]);
+            return;
+        };
+        if let Some(Route::Parallel(routes, levels)) = self.get_mut(parent_path) {
+            levels.resize(routes.len(), 1.0);
+            if *index < routes.len() {
+                levels.remove(*index);
+            }
+        }
+        if let Some(Route::Series(routes) | Route::Parallel(routes, _)) = self.get_mut(parent_path) {
+            if *index < routes.len() {
+                routes.remove(*index);
+            }
+        }
+        self.simplify();
+    }
+
+    /// Level of the parallel branch at `path`, `None` if it isn't a parallel branch
+    pub fn level(&self, path: &[usize]) -> Option<f32> {
+        let (index, parent_path) = path.split_last()?;
+        match self.get(parent_path)? {
+            Route::Parallel(routes, levels) if *index < routes.len() => {
+                Some(levels.get(*index).copied().unwrap_or(1.0))
+            },
+            _ => None,
+        }
+    }
+
+    /// Set the level of the parallel branch at `path`, clamped to [0, [`MAX_LEVEL`]]
+    pub fn set_level(&mut self, path: &[usize], level: f32) {
+        let Some((index, parent_path)) = path.split_last() else { return };
+        if let Some(Route::Parallel(routes, levels)) = self.get_mut(parent_path) {
+            levels.resize(routes.len(), 1.0);
+            if let Some(l) = levels.get_mut(*index) {
+                *l = level.clamp(0.0, MAX_LEVEL);
+            }
+        }
+    }
+
+    /// Drop empty groups and replace groups with a single route by that route
+    fn simplify(&mut self) {
+        let (Route::Series(routes) | Route::Parallel(routes, _)) = self else { return };
+        for route in routes.iter_mut() {
+            route.simplify();
+            if let Route::Series(inner) | Route::Parallel(inner, _) = route {
+                if inner.len() == 1 {
+                    *route = inner.remove(0);
+                }
+            }
+        }
+        let empty = |route: &Route| matches!(route, Route::Series(r) | Route::Parallel(r, _) if r.is_empty());
+        if let Route::Parallel(routes, levels) = self {
+            levels.resize(routes.len(), 1.0);
+            let mut keep = routes.iter().map(|route| !empty(route));
+            levels.retain(|_| keep.next().unwrap_or(true));
+        }
+        if let Route::Series(routes) | Route::Parallel(routes, _) = self {
+            routes.retain(|route| !empty(route));
+        }
+    }
+}
+
+/// Instructions of the compiled graph, they run on a stack holding the signal of
+/// each open parallel branch
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    /// Process the top of the stack with a node, using the state in a slot
+    Node(NodeKind, usize),
+    /// Keep a copy of the top of the stack for the next parallel branch
+    Dup,
+    Swap,
+    /// Scale the top of the stack by the level of a parallel branch
+    Level(f32),
+    /// Average the top values of the stack
+    Mix(usize),
+}
+
+impl Op {
+    fn encode(&self) -> (u32, u32) {
+        match self {
+            Op::Node(kind, slot) => {
+                let kind = NodeKind::ALL.iter().position(|k| k == kind).unwrap_or(0);
+                (0, (kind << 16 | slot) as u32)
+            },
+            Op::Dup       => (1, 0),
+            Op::Swap      => (2, 0),
+            Op::Mix(n)    => (3, *n as u32),
+            Op::Level(l)  => (4, l.to_bits()),
+        }
+    }
+
+    fn decode(op: u32, arg: u32) -> Option<Op> {
+        Some(match op {
+            0 => Op::Node(*NodeKind::ALL.get((arg >> 16) as usize)?, (arg & 0xffff) as usize),
+            1 => Op::Dup,
+            2 => Op::Swap,
+            3 => Op::Mix(arg as usize),
+            4 => Op::Level(f32::from_bits(arg)),
+            _ => return None,
+        })
+    }
+}
+
+/// Compile a route to instructions, every node gets its own state slot
+fn compile(route: &Route, ops: &mut Vec<Op>, slots: &mut usize) {
+    match route {
+        Route::Node(kind) => {
+            ops.push(Op::Node(*kind, *slots));
+            *slots += 1;
+        },
+        Route::Series(routes) => {
+            for route in routes {
+                compile(route, ops, slots);
+            }
+        },
+        Route::Parallel(routes, levels) => {
+            for (i, route) in routes.iter().enumerate() {
+                let level = levels.get(i).copied().unwrap_or(1.0);
+                if i + 1 < routes.len() {
+                    ops.push(Op::Dup);
+                    compile(route, ops, slots);
+                    if level != 1.0 {
+                        ops.push(Op::Level(level));
+                    }
+                    ops.push(Op::Swap);
+                } else {
+                    compile(route, ops, slots);
+                    if level != 1.0 {
+                        ops.push(Op::Level(level));
+                    }
+                }
+            }
+            if routes.len() > 1 {
+                ops.push(Op::Mix(routes.len()));
+            }
+        },
+    }
+}
+
+/// A compiled graph that can be run from the audio thread. Like the expression
+/// function two programs are kept so a new one is written to the unused one and
+/// then swapped in, without locking or allocating
+pub struct RouteSlot {
+    ops: [Vec<AtomicU32>; 2],
+    args: [Vec<AtomicU32>; 2],
+    lens: [AtomicUsize; 2],
+    /// [`Route::shaper_depth`] of the graph
+    shaper_depth: AtomicUsize,
+    /// Index of the program the audio thread runs
+    active: AtomicUsize,
+}
+
+impl RouteSlot {
+    pub fn new(route: &Route) -> Self {
+        let slot = Self {
+            ops: std::array::from_fn(|_| (0..MAX_OPS).map(|_| AtomicU32::new(0)).collect()),
+            args: std::array::from_fn(|_| (0..MAX_OPS).map(|_| AtomicU32::new(0)).collect()),
+            lens: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            shaper_depth: AtomicUsize::new(0),
+            active: AtomicUsize::new(0),
+        };
+        slot.load(route);
+        slot
+    }
+
+    /// Compile a graph and swap it in. Not meant to be called from the audio thread
+    pub fn load(&self, route: &Route) {
+        let mut ops = Vec::new();
+        compile(route, &mut ops, &mut 0);
+        // Graphs are limited to MAX_NODES nodes, which always fit. Cutting a longer
+        // program short would leave parallel branches open, so it isn't swapped in
+        if ops.len() > MAX_OPS {
+            return;
+        }
+
+        let next = 1 - self.active.load(Ordering::Acquire);
+        for (i, op) in ops.iter().enumerate() {
+            let (op, arg) = op.encode();
+            self.ops[next][i].store(op, Ordering::Relaxed);
+            self.args[next][i].store(arg, Ordering::Relaxed);
+        }
+        self.lens[next].store(ops.len(), Ordering::Relaxed);
+        self.active.store(next, Ordering::Release);
+        self.shaper_depth.store(route.shaper_depth(), Ordering::Relaxed);
+    }
+
+    /// Largest number of waveshapers a signal goes through in the loaded graph
+    pub fn shaper_depth(&self) -> usize {
+        self.shaper_depth.load(Ordering::Relaxed)
+    }
+
+    /// Run a sample through the graph. `node` processes a sample with a node and the
+    /// index of the node's state, which is below [`MAX_NODES`]
+    pub fn run(&self, x: f32, mut node: impl FnMut(NodeKind, usize, f32) -> f32) -> f32 {
+        let active = self.active.load(Ordering::Acquire);
+        let len = self.lens[active].load(Ordering::Relaxed).min(MAX_OPS);
+
+        let mut stack = [0.0f32; MAX_STACK];
+        stack[0] = x;
+        let mut depth = 1;
+        for i in 0..len {
+            let op = Op::decode(
+                self.ops[active][i].load(Ordering::Relaxed),
+                self.args[active][i].load(Ordering::Relaxed),
+            );
+            match op {
+                Some(Op::Node(kind, slot)) if slot < MAX_NODES => {
+                    stack[depth - 1] = node(kind, slot, stack[depth - 1]);
+                },
+                Some(Op::Dup) if depth < MAX_STACK => {
+                    stack[depth] = stack[depth - 1];
+                    depth += 1;
+                },
+                Some(Op::Swap) if depth >= 2 => stack.swap(depth - 1, depth - 2),
+                Some(Op::Level(level)) => stack[depth - 1] *= level,
+                Some(Op::Mix(n)) => {
+                    // Always leave the last value on the stack
+                    let n = n.clamp(1, depth);
+                    let sum = stack[depth - n..depth].iter().sum::<f32>();
+                    depth -= n - 1;
+                    stack[depth - 1] = sum / n as f32;
+                },
+                _ => (),
+            }
+        }
+        stack[depth - 1]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes() {
+        let mut route = Route::default();
+        // Run a waveshaper in parallel with the post gain
+        let path = route.insert(&[4], NodeKind::Shaper(1), true).unwrap();
+        assert_eq!(path, vec![4, 1]);
+        assert_eq!(route.node_count(), 6);
+        assert_eq!(route.shaper_depth(), 2);
+
+        let slot = RouteSlot::new(&route);
+        let y = slot.run(1.0, |kind, _, x| match kind {
+            NodeKind::PreGain   => x * 2.0,
+            NodeKind::Shaper(0) => x + 1.0,
+            NodeKind::PostGain  => x * 4.0,
+            NodeKind::Shaper(_) => x * 0.0,
+            _ => x,
+        });
+        // ((1 * 2 + 1) * 4 + 0) / 2
+        assert_eq!(y, 6.0);
+
+        // Turn the post gain branch down
+        let post_gain = [4, 0];
+        assert_eq!(route.level(&post_gain), Some(1.0));
+        route.set_level(&post_gain, 0.5);
+        assert_eq!(route.level(&post_gain), Some(0.5));
+        assert_eq!(route.level(&[4]), None);
+        let slot = RouteSlot::new(&route);
+        let y = slot.run(1.0, |kind, _, x| match kind {
+            NodeKind::PreGain   => x * 2.0,
+            NodeKind::Shaper(0) => x + 1.0,
+            NodeKind::PostGain  => x * 4.0,
+            NodeKind::Shaper(_) => x * 0.0,
+            _ => x,
+        });
+        // ((1 * 2 + 1) * 4 * 0.5 + 0) / 2
+        assert_eq!(y, 3.0);
+
+        route.remove(&path);
+        assert_eq!(route, Route::default());
+    }
+    #[test]
+    fn repairs_loaded_routes() {
+        // Too many parallel branches, an unknown waveshaper stage and a level out of range
+        let branches = vec![r#"{"Node":"PreGain"}"#; MAX_NODES + 4].join(",");
+        let json = format!(
+            r#"{{"Parallel":[[{{"Node":{{"Shaper":7}}}},{},{{"Node":"PostGain"}}],[1.0,0.5,9.0]]}}"#,
+            branches,
+        );
+        let route: Route = serde_json::from_str(&json).unwrap();
+        assert_eq!(route.node_count(), MAX_NODES);
+        assert_eq!(route.shaper_depth(), 0);
+        let Route::Series(routes) = &route else { panic!("{:?}", route) };
+        let Route::Parallel(branches, levels) = &routes[0] else { panic!("{:?}", route) };
+        assert_eq!(branches.len(), levels.len());
+        assert_eq!(&levels[..2], &[0.5, MAX_LEVEL]);
+
+        let mut ops = Vec::new();
+        compile(&route, &mut ops, &mut 0);
+        assert!(ops.len() <= MAX_OPS);
+        let slot = RouteSlot::new(&route);
+        let y = slot.run(1.0, |kind, _, x| match kind {
+            NodeKind::PreGain => x * 2.0,
+            _ => x,
+        });
+        // (2 * 0.5 + 2 * 2 + 2 * 14) / 16
+        assert_eq!(y, 33.0 / 16.0);
+
+        let route: Route = serde_json::from_str(r#"{"Node":{"Shaper":4}}"#).unwrap();
+        assert_eq!(route, Route::Series(vec![]));
+    }
+}
@@ -0,0 +1,125 @@
+use std::f32::consts::PI;
+use nih_plug::prelude::*;
+
+#[derive(Clone, Copy, Debug, Enum, PartialEq)]
+pub enum LfoShape {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    #[name = "Sample & Hold"]
+    SampleAndHold,
+}
+
+/// Note lengths an LFO can be synced to
+#[derive(Clone, Copy, Debug, Enum, PartialEq)]
+pub enum SyncRate {
+    #[name = "4 bars"]
+    FourBars,
+    #[name = "2 bars"]
+    TwoBars,
+    #[name = "1 bar"]
+    Bar,
+    #[name = "1/2"]
+    Half,
+    #[name = "1/4 D"]
+    QuarterDotted,
+    #[name = "1/4"]
+    Quarter,
+    #[name = "1/4 T"]
+    QuarterTriplet,
+    #[name = "1/8 D"]
+    EighthDotted,
+    #[name = "1/8"]
+    Eighth,
+    #[name = "1/8 T"]
+    EighthTriplet,
+    #[name = "1/16"]
+    Sixteenth,
+    #[name = "1/32"]
+    ThirtySecond,
+}
+
+impl SyncRate {
+    /// Length of one cycle in quarter notes (assuming 4/4)
+    pub fn beats(&self) -> f64 {
+        use SyncRate::*;
+        match self {
+            FourBars       => 16.0,
+            TwoBars        => 8.0,
+            Bar            => 4.0,
+            Half           => 2.0,
+            QuarterDotted  => 1.5,
+            Quarter        => 1.0,
+            QuarterTriplet => 2.0 / 3.0,
+            EighthDotted   => 0.75,
+            Eighth         => 0.5,
+            EighthTriplet  => 1.0 / 3.0,
+            Sixteenth      => 0.25,
+            ThirtySecond   => 0.125,
+        }
+    }
+}
+
+/// A low frequency oscillator outputting values in [-1, 1]
+#[derive(Clone, Copy)]
+pub struct Lfo {
+    /// Phase in [0, 1)
+    phase: f32,
+    /// Held value of the sample & hold shape
+    held: f32,
+    /// Xorshift state for the sample & hold shape
+    rng: u32,
+}
+
+impl Lfo {
+    pub fn new(seed: u32) -> Self {
+        let mut lfo = Self {
+            phase: 0.0,
+            held: 0.0,
+            rng: seed.max(1),
+        };
+        lfo.held = lfo.random();
+        lfo
+    }
+
+    /// Random value in [-1, 1]
+    fn random(&mut self) -> f32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+
+    /// Jump to a phase, used to follow the host's transport
+    pub fn set_phase(&mut self, phase: f32) {
+        let phase = phase.rem_euclid(1.0);
+        if phase < self.phase {
+            self.held = self.random();
+        }
+        self.phase = phase;
+    }
+
+    /// Get the current value and advance the phase by `increment` cycles
+    pub fn next(&mut self, shape: LfoShape, increment: f32) -> f32 {
+        let p = self.phase;
+        let value = match shape {
+            LfoShape::Sine          => (2.0 * PI * p).sin(),
+            LfoShape::Triangle      => 1.0 - 4.0 * (p - 0.5).abs(),
+            LfoShape::Saw           => 2.0 * p - 1.0,
+            LfoShape::Square        => if p < 0.5 { 1.0 } else { -1.0 },
+            LfoShape::SampleAndHold => self.held,
+        };
+
+        self.phase += increment;
+        if self.phase >= 1.0 {
+            self.phase = self.phase.fract();
+            self.held = self.random();
+        }
+        value
+    }
+
+    pub fn reset(&mut self) {
+        self.phase = 0.0;
+    }
+}
@@ -0,0 +1,139 @@
+/// Release time of the peak level in seconds
+const PEAK_RELEASE: f32 = 0.3;
+/// Averaging time of the RMS level in seconds
+const RMS_TIME: f32 = 0.3;
+
+/// Peak and RMS ballistics of a level meter channel
+#[derive(Clone, Copy, Default)]
+pub struct LevelMeter {
+    peak: f32,
+    /// Averaged power
+    power: f32,
+    peak_coeff: f32,
+    rms_coeff: f32,
+}
+
+impl LevelMeter {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            peak: 0.0,
+            power: 0.0,
+            peak_coeff: (-1.0 / (PEAK_RELEASE * sample_rate)).exp(),
+            rms_coeff: (-1.0 / (RMS_TIME * sample_rate)).exp(),
+        }
+    }
+
+    pub fn process(&mut self, x: f32) {
+        let level = x.abs();
+        // Instant attack, exponential release
+        self.peak = level.max(self.peak * self.peak_coeff);
+        self.power = x * x + self.rms_coeff * (self.power - x * x);
+    }
+
+    pub fn peak(&self) -> f32 {
+        self.peak
+    }
+
+    pub fn rms(&self) -> f32 {
+        self.power.sqrt()
+    }
+
+    pub fn reset(&mut self) {
+        self.peak = 0.0;
+        self.power = 0.0;
+    }
+}
+
+/// Number of segments the clip activity window is split into, the window moves by
+/// one segment at a time
+const SEGMENTS: usize = 10;
+/// Length of the clip activity window in seconds
+const WINDOW: f32 = 1.0;
+
+#[derive(Clone, Copy, Default)]
+struct Segment {
+    samples: usize,
+    clipped: usize,
+    /// Largest ratio between a sample and the clip threshold
+    overshoot: f32,
+}
+
+/// How often and how hard the final clip engaged over the last second
+#[derive(Clone, Copy)]
+pub struct ClipActivity {
+    segments: [Segment; SEGMENTS],
+    current: usize,
+    /// Frames per segment
+    segment_len: usize,
+    /// Frames counted in the current segment
+    frames: usize,
+}
+
+impl ClipActivity {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            segments: [Segment::default(); SEGMENTS],
+            current: 0,
+            segment_len: ((WINDOW * sample_rate) as usize / SEGMENTS).max(1),
+            frames: 0,
+        }
+    }
+
+    /// Count a frame of `samples` samples of which `clipped` went over the threshold,
+    /// `overshoot` is the largest ratio between a sample and the threshold
+    pub fn process(&mut self, samples: usize, clipped: usize, overshoot: f32) {
+        if self.frames == self.segment_len {
+            self.current = (self.current + 1) % SEGMENTS;
+            self.segments[self.current] = Segment::default();
+            self.frames = 0;
+        }
+        let segment = &mut self.segments[self.current];
+        segment.samples += samples;
+        segment.clipped += clipped;
+        if clipped > 0 {
+            segment.overshoot = segment.overshoot.max(overshoot);
+        }
+        self.frames += 1;
+    }
+
+    /// Fraction of the samples that got clipped
+    pub fn clipped_ratio(&self) -> f32 {
+        let (samples, clipped) = self.segments.iter()
+            .fold((0, 0), |(samples, clipped), s| (samples + s.samples, clipped + s.clipped));
+        clipped as f32 / samples.max(1) as f32
+    }
+
+    /// Largest ratio between a clipped sample and the threshold, 0 if nothing clipped
+    pub fn max_overshoot(&self) -> f32 {
+        self.segments.iter().fold(0.0, |max, s| max.max(s.overshoot))
+    }
+
+    pub fn reset(&mut self) {
+        self.segments = [Segment::default(); SEGMENTS];
+        self.frames = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clip_activity_window() {
+        let mut activity = ClipActivity::new(1000.0);
+        for i in 0..1000 {
+            // Every fourth frame clips one of its two samples
+            let clipped = if i % 4 == 0 { 1 } else { 0 };
+            activity.process(2, clipped, 2.0);
+        }
+        assert!((activity.clipped_ratio() - 0.125).abs() < 1e-3);
+        assert_eq!(activity.max_overshoot(), 2.0);
+
+        // A second without clipping clears it
+        for _ in 0..1000 {
+            activity.process(2, 0, 0.0);
+        }
+        assert_eq!(activity.clipped_ratio(), 0.0);
+        assert_eq!(activity.max_overshoot(), 0.0);
+    }
+}
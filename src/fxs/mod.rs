@@ -1,3 +1,19 @@
 pub mod waveshaper;
 pub mod filter;
-pub mod utils;
\ No newline at end of file
+pub mod utils;
+pub mod oversample;
+pub mod delay;
+pub mod adaa;
+pub mod lfo;
+pub mod envelope;
+pub mod adsr;
+pub mod crossover;
+pub mod stereo;
+pub mod curve;
+pub mod expression;
+pub mod graph;
+pub mod feedback;
+pub mod dc_blocker;
+pub mod auto_gain;
+pub mod meter;
+pub mod spectrum;
@@ -0,0 +1,263 @@
+use std::f32::consts::PI;
+use nih_plug::prelude::*;
+
+/// Number of non-zero taps on each side of the half-band kernel's centre tap
+const HALF_TAPS: usize = 8;
+/// Length of the whole half-band kernel (every other tap is zero)
+const KERNEL_LEN: usize = 4 * HALF_TAPS - 1;
+/// Centre tap of the kernel, also the group delay of a single filter
+const CENTER: usize = 2 * HALF_TAPS - 1;
+
+/// Maximum number of 2x stages (16x)
+pub const MAX_STAGES: usize = 4;
+/// Maximum oversampling factor
+pub const MAX_FACTOR: usize = 1 << MAX_STAGES;
+/// Largest delay of the oversampled function that can be compensated, in
+/// oversampled samples
+pub const MAX_INNER_DELAY: usize = 16;
+/// Upper bound of [`Oversampler::latency`] for any factor
+pub const MAX_LATENCY: usize = 2 * CENTER + MAX_INNER_DELAY;
+
+#[derive(Clone, Copy, Debug, Enum, PartialEq)]
+pub enum OversamplingFactor {
+    #[name = "Off"]
+    X1,
+    #[name = "2x"]
+    X2,
+    #[name = "4x"]
+    X4,
+    #[name = "8x"]
+    X8,
+    #[name = "16x"]
+    X16,
+}
+
+impl OversamplingFactor {
+    /// Number of 2x stages needed for this factor
+    pub fn stages(&self) -> usize {
+        *self as usize
+    }
+
+    /// The oversampling ratio
+    pub fn ratio(&self) -> usize {
+        1 << self.stages()
+    }
+}
+
+/// Half-band coefficients (windowed sinc), only the non-zero side taps are stored.
+/// `coeffs[i]` is the tap at `CENTER ± (2i + 1)`, the centre tap is always 0.5
+fn half_band_coeffs() -> [f32; HALF_TAPS] {
+    let mut coeffs = [0.0; HALF_TAPS];
+    for (i, c) in coeffs.iter_mut().enumerate() {
+        let n = CENTER - (2 * i + 1);
+        let d = (2 * i + 1) as f32;
+        // sinc(d / 2) * 0.5
+        let sinc = (PI * d * 0.5).sin() / (PI * d);
+        // Blackman window
+        let w = n as f32 / (KERNEL_LEN - 1) as f32;
+        let window = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+        *c = sinc * window;
+    }
+    // Normalize for unity gain at DC
+    let sum = coeffs.iter().sum::<f32>() * 2.0;
+    for c in &mut coeffs {
+        *c *= 0.5 / sum;
+    }
+    coeffs
+}
+
+/// A single 2x polyphase half-band stage, holds the state for both directions
+#[derive(Clone, Copy)]
+struct HalfBand {
+    coeffs: [f32; HALF_TAPS],
+    /// Input history of the upsampler (at the low rate), newest first
+    up: [f32; 2 * HALF_TAPS],
+    /// Input history of the downsampler (at the high rate), newest first
+    down: [f32; KERNEL_LEN + 1],
+}
+
+impl HalfBand {
+    fn new(coeffs: [f32; HALF_TAPS]) -> Self {
+        Self {
+            coeffs,
+            up: [0.0; 2 * HALF_TAPS],
+            down: [0.0; KERNEL_LEN + 1],
+        }
+    }
+
+    /// Turn one sample into two. Only the even phase needs filtering, the odd phase
+    /// lands on the centre tap and is just a delayed copy of the input
+    fn upsample(&mut self, x: f32) -> (f32, f32) {
+        self.up.copy_within(0..2 * HALF_TAPS - 1, 1);
+        self.up[0] = x;
+
+        let mut even = 0.0;
+        for (i, c) in self.coeffs.iter().enumerate() {
+            even += c * (self.up[HALF_TAPS - 1 - i] + self.up[HALF_TAPS + i]);
+        }
+        (2.0 * even, self.up[HALF_TAPS - 1])
+    }
+
+    /// Turn two samples into one. The output is centred one sample further back so
+    /// the stage delay is a whole number of low rate samples
+    fn downsample(&mut self, a: f32, b: f32) -> f32 {
+        self.down.copy_within(0..KERNEL_LEN - 1, 2);
+        self.down[1] = a;
+        self.down[0] = b;
+
+        let mid = CENTER + 1;
+        let mut y = 0.5 * self.down[mid];
+        for (i, c) in self.coeffs.iter().enumerate() {
+            let d = 2 * i + 1;
+            y += c * (self.down[mid - d] + self.down[mid + d]);
+        }
+        y
+    }
+
+    fn reset(&mut self) {
+        self.up = [0.0; 2 * HALF_TAPS];
+        self.down = [0.0; KERNEL_LEN + 1];
+    }
+}
+
+/// Cascaded 2x half-band oversampler, runs a function at up to 16 times the sample rate
+#[derive(Clone, Copy)]
+pub struct Oversampler {
+    stages: [HalfBand; MAX_STAGES],
+    factor: OversamplingFactor,
+    /// Delay of the function run at the highest rate, in oversampled samples
+    inner_delay: usize,
+    /// Delay at the highest rate to round the total latency to whole samples
+    pad: [f32; MAX_FACTOR],
+    pad_len: usize,
+    pad_pos: usize,
+}
+
+impl Default for Oversampler {
+    fn default() -> Self {
+        Self {
+            stages: [HalfBand::new(half_band_coeffs()); MAX_STAGES],
+            factor: OversamplingFactor::X1,
+            inner_delay: 0,
+            pad: [0.0; MAX_FACTOR],
+            pad_len: 0,
+            pad_pos: 0,
+        }
+    }
+}
+
+impl Oversampler {
+    pub fn factor(&self) -> OversamplingFactor {
+        self.factor
+    }
+
+    pub fn inner_delay(&self) -> usize {
+        self.inner_delay
+    }
+
+    /// Change the oversampling factor and the delay of the function run at the
+    /// oversampled rate (in oversampled samples, up to [`MAX_INNER_DELAY`]), this also
+    /// resets the filters
+    pub fn set_factor(&mut self, factor: OversamplingFactor, inner_delay: usize) {
+        self.factor = factor;
+        self.inner_delay = inner_delay.min(MAX_INNER_DELAY);
+        let ratio = factor.ratio();
+        let delay = self.delay();
+        self.pad_len = (ratio - delay % ratio) % ratio;
+        self.reset();
+    }
+
+    /// Delay at the highest rate before padding. Every stage is delayed by `CENTER`
+    /// samples at its own low rate
+    fn delay(&self) -> usize {
+        CENTER * (2 * self.factor.ratio() - 2) + self.inner_delay
+    }
+
+    /// Latency in samples at the host's sample rate, including the function's delay
+    pub fn latency(&self) -> u32 {
+        ((self.delay() + self.pad_len) / self.factor.ratio()) as u32
+    }
+
+    /// Process a sample, running `f` on every oversampled sample
+    pub fn process(&mut self, x: f32, mut f: impl FnMut(f32) -> f32) -> f32 {
+        let stages = self.factor.stages();
+        if stages == 0 {
+            return f(x);
+        }
+
+        let mut buf = [0.0; MAX_FACTOR];
+        let mut tmp = [0.0; MAX_FACTOR];
+        buf[0] = x;
+
+        // Upsample
+        let mut len = 1;
+        for stage in &mut self.stages[..stages] {
+            for i in 0..len {
+                let (a, b) = stage.upsample(buf[i]);
+                tmp[2 * i] = a;
+                tmp[2 * i + 1] = b;
+            }
+            len *= 2;
+            buf[..len].copy_from_slice(&tmp[..len]);
+        }
+
+        // Process
+        for s in &mut buf[..len] {
+            let x = if self.pad_len > 0 {
+                let delayed = self.pad[self.pad_pos];
+                self.pad[self.pad_pos] = *s;
+                self.pad_pos = (self.pad_pos + 1) % self.pad_len;
+                delayed
+            } else {
+                *s
+            };
+            *s = f(x);
+        }
+
+        // Downsample
+        for stage in self.stages[..stages].iter_mut().rev() {
+            len /= 2;
+            for i in 0..len {
+                buf[i] = stage.downsample(buf[2 * i], buf[2 * i + 1]);
+            }
+        }
+
+        buf[0]
+    }
+
+    pub fn reset(&mut self) {
+        for stage in &mut self.stages {
+            stage.reset();
+        }
+        self.pad = [0.0; MAX_FACTOR];
+        self.pad_pos = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_is_aligned() {
+        for factor in OversamplingFactor::variants().iter().enumerate().map(|(i, _)| OversamplingFactor::from_index(i)) {
+            // The function delays by `inner_delay` oversampled samples
+            for inner_delay in [0, 1, 3] {
+                let mut os = Oversampler::default();
+                os.set_factor(factor, inner_delay);
+                let latency = os.latency() as usize;
+                assert!(latency <= MAX_LATENCY);
+
+                let mut history = std::collections::VecDeque::from(vec![0.0; inner_delay]);
+                let input = (0..1000).map(|n| (n as f32 * 0.05).sin()).collect::<Vec<_>>();
+                let output = input.iter().map(|x| os.process(*x, |s| {
+                    history.push_back(s);
+                    history.pop_front().unwrap()
+                })).collect::<Vec<_>>();
+                for n in 200..1000 {
+                    assert!((output[n] - input[n - latency]).abs() < 1e-4);
+                }
+            }
+        }
+    }
+}
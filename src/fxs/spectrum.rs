@@ -0,0 +1,255 @@
+use std::f32::consts::PI;
+use serde::{Deserialize, Serialize};
+
+/// Largest FFT size, the history the audio thread keeps needs to be at least this long
+pub const MAX_FFT_SIZE: usize = 8192;
+
+/// FFT size of the spectrum analyser, larger sizes show more detail in the low end
+/// but react slower
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Resolution {
+    Fft1024,
+    Fft2048,
+    Fft4096,
+    Fft8192,
+}
+
+impl Resolution {
+    pub const ALL: [Resolution; 4] = [
+        Resolution::Fft1024,
+        Resolution::Fft2048,
+        Resolution::Fft4096,
+        Resolution::Fft8192,
+    ];
+
+    pub fn size(self) -> usize {
+        match self {
+            Resolution::Fft1024 => 1024,
+            Resolution::Fft2048 => 2048,
+            Resolution::Fft4096 => 4096,
+            Resolution::Fft8192 => MAX_FFT_SIZE,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.size().to_string()
+    }
+
+    /// The next resolution, wrapping around to the smallest
+    pub fn next(self) -> Self {
+        let i = Self::ALL.iter().position(|r| *r == self).unwrap_or(0);
+        Self::ALL[(i + 1) % Self::ALL.len()]
+    }
+}
+
+/// How long the spectrum is smoothed over
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Averaging {
+    Off,
+    Fast,
+    Medium,
+    Slow,
+}
+
+impl Averaging {
+    pub const ALL: [Averaging; 4] = [
+        Averaging::Off,
+        Averaging::Fast,
+        Averaging::Medium,
+        Averaging::Slow,
+    ];
+
+    /// Time constant in seconds
+    pub fn time(self) -> f32 {
+        match self {
+            Averaging::Off    => 0.0,
+            Averaging::Fast   => 0.05,
+            Averaging::Medium => 0.15,
+            Averaging::Slow   => 0.5,
+        }
+    }
+
+    /// Averaging coefficient for [`Analyser::process`] when the spectrum is analysed
+    /// `update_rate` times per second
+    pub fn coefficient(self, update_rate: f32) -> f32 {
+        if self.time() > 0.0 {
+            (-1.0 / (self.time() * update_rate)).exp()
+        } else {
+            0.0
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Averaging::Off    => String::from("off"),
+            Averaging::Fast   => String::from("fast"),
+            Averaging::Medium => String::from("medium"),
+            Averaging::Slow   => String::from("slow"),
+        }
+    }
+
+    /// The next averaging, wrapping around to off
+    pub fn next(self) -> Self {
+        let i = Self::ALL.iter().position(|a| *a == self).unwrap_or(0);
+        Self::ALL[(i + 1) % Self::ALL.len()]
+    }
+}
+
+/// Settings of the spectrum analyser. They only change what the editor shows, so
+/// they are saved with the plugin state instead of being parameters
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub resolution: Resolution,
+    pub averaging: Averaging,
+    /// Draw the highest level each frequency reached
+    pub peak_hold: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            resolution: Resolution::Fft4096,
+            averaging: Averaging::Medium,
+            peak_hold: false,
+        }
+    }
+}
+
+/// In-place radix-2 FFT, the length needs to be a power of two
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    debug_assert!(n.is_power_of_two() && im.len() == n);
+
+    // Bit reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    // Butterflies
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_im, w_re) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// Magnitude spectrum of a signal with averaging and falling peaks. Runs on the GUI
+/// thread so it's free to allocate when the resolution changes
+#[derive(Clone, Default)]
+pub struct Analyser {
+    /// Hann window of the current size
+    window: Vec<f32>,
+    re: Vec<f32>,
+    im: Vec<f32>,
+    /// Averaged magnitude of each bin
+    magnitudes: Vec<f32>,
+    /// Highest averaged magnitude of each bin, falling slowly
+    peaks: Vec<f32>,
+}
+
+impl Analyser {
+    /// Analyse the latest samples. `averaging` goes from 0 (no averaging) to 1
+    /// (frozen) and the held peaks are multiplied by `peak_decay` before they are
+    /// compared to the new magnitudes
+    pub fn process(&mut self, samples: &[f32], resolution: Resolution, averaging: f32, peak_decay: f32) {
+        let size = resolution.size().min(samples.len());
+        if self.window.len() != size {
+            self.resize(size);
+        }
+
+        let samples = &samples[samples.len() - size..];
+        for (i, (x, w)) in samples.iter().zip(&self.window).enumerate() {
+            self.re[i] = x * w;
+            self.im[i] = 0.0;
+        }
+        fft(&mut self.re, &mut self.im);
+
+        // Scale so a full scale sine reads as 1
+        let scale = 2.0 / self.window.iter().sum::<f32>();
+        for (i, (magnitude, peak)) in self.magnitudes.iter_mut().zip(&mut self.peaks).enumerate() {
+            let m = (self.re[i] * self.re[i] + self.im[i] * self.im[i]).sqrt() * scale;
+            *magnitude = m + averaging * (*magnitude - m);
+            *peak = (*peak * peak_decay).max(*magnitude);
+        }
+    }
+
+    fn resize(&mut self, size: usize) {
+        self.window = (0..size)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / size as f32).cos())
+            .collect();
+        self.re = vec![0.0; size];
+        self.im = vec![0.0; size];
+        self.magnitudes = vec![0.0; size / 2 + 1];
+        self.peaks = vec![0.0; size / 2 + 1];
+    }
+
+    /// FFT size of the last analysis, 0 before the first one
+    pub fn size(&self) -> usize {
+        self.window.len()
+    }
+
+    /// Averaged magnitude of each bin, from DC to Nyquist
+    pub fn magnitudes(&self) -> &[f32] {
+        &self.magnitudes
+    }
+
+    /// Held peak of each bin, from DC to Nyquist
+    pub fn peaks(&self) -> &[f32] {
+        &self.peaks
+    }
+
+    pub fn reset_peaks(&mut self) {
+        self.peaks.iter_mut().for_each(|peak| *peak = 0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_sine() {
+        let size = Resolution::Fft1024.size();
+        let bin = 50;
+        let samples: Vec<f32> = (0..size)
+            .map(|i| (2.0 * PI * bin as f32 * i as f32 / size as f32).sin() * 0.5)
+            .collect();
+
+        let mut analyser = Analyser::default();
+        analyser.process(&samples, Resolution::Fft1024, 0.0, 1.0);
+        let magnitudes = analyser.magnitudes();
+        assert!((magnitudes[bin] - 0.5).abs() < 1e-3);
+        // The Hann window leaks into the next bins only
+        assert!(magnitudes[bin + 3] < 1e-4);
+        assert!(magnitudes[bin - 3] < 1e-4);
+        assert_eq!(analyser.peaks()[bin], magnitudes[bin]);
+
+        // The peak falls once the sine is gone
+        analyser.process(&vec![0.0; size], Resolution::Fft1024, 0.0, 0.5);
+        assert!((analyser.peaks()[bin] - 0.25).abs() < 1e-3);
+        assert_eq!(analyser.magnitudes()[bin], 0.0);
+    }
+}
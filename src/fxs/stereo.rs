@@ -0,0 +1,25 @@
+use nih_plug::prelude::*;
+
+/// How the two channels are processed
+#[derive(Clone, Copy, Debug, Enum, PartialEq)]
+pub enum StereoMode {
+    /// Both channels share the same settings
+    #[name = "Stereo Linked"]
+    Linked,
+    /// Left and right get their own drive and mix
+    #[name = "Left/Right"]
+    LeftRight,
+    /// The signal is split into mid and side, which get their own drive and mix
+    #[name = "Mid/Side"]
+    MidSide,
+}
+
+/// Convert left/right to mid/side
+pub fn encode([l, r]: [f32; 2]) -> [f32; 2] {
+    [(l + r) * 0.5, (l - r) * 0.5]
+}
+
+/// Convert mid/side back to left/right
+pub fn decode([m, s]: [f32; 2]) -> [f32; 2] {
+    [m + s, m - s]
+}
@@ -1,4 +1,11 @@
-use crate::fxs::utils::hard_clip;
+use crate::{
+    fxs::{
+        curve::{Curve, CurveTable},
+        expression::{self, ExpressionSlot, Program},
+        utils::{hard_clip, mix_between},
+    },
+    params::TriState,
+};
 use std::f32::consts::PI;
 use nih_plug::{prelude::*, util::gain_to_db};
 
@@ -36,10 +43,77 @@ pub enum FunctionType {
     Floor,
     Round,
     Bitcrush,
+    // Curve drawn by the user, spanning [-t, t]
+    // t * curve(x / t)
+    Custom,
+    // Formula typed by the user
+    Expression,
+}
+
+/// Waveshaper functions defined by the user, shared between the editor and the
+/// audio thread
+pub struct UserFunctions {
+    pub curve: CurveTable,
+    pub expression: ExpressionSlot,
+}
+
+impl Default for UserFunctions {
+    fn default() -> Self {
+        Self {
+            curve: CurveTable::new(&Curve::default()),
+            expression: ExpressionSlot::new(
+                &Program::compile(expression::DEFAULT_EXPRESSION).unwrap_or_default(),
+            ),
+        }
+    }
 }
 
 const PI2: f32 = 2.0 * PI;
 
+/// Maximum number of waveshapers in the chain, including the main one
+pub const MAX_STAGES: usize = 4;
+
+/// Dilogarithm Li2(z) for -1 <= z <= 0.5
+fn li2(z: f64) -> f64 {
+    if z < -0.5 {
+        // Landen's identity maps z into [1/3, 1/2] where the series converges fast
+        let w = z / (z - 1.0);
+        let l = (1.0 - z).ln();
+        return -0.5 * l * l - li2(w);
+    }
+    let mut sum = 0.0;
+    let mut zk = z;
+    for k in 1..=40 {
+        sum += zk / (k * k) as f64;
+        zk *= z;
+    }
+    sum
+}
+
+/// ln(cosh(u)) without overflowing
+fn ln_cosh(u: f64) -> f64 {
+    let ua = u.abs();
+    ua + (-2.0 * ua).exp().ln_1p() - std::f64::consts::LN_2
+}
+
+/// Integral of ln(cosh(s)) from 0 to u
+fn ln_cosh_integral(u: f64) -> f64 {
+    let ua = u.abs();
+    let i = ua * ua * 0.5 - ua * std::f64::consts::LN_2
+        + 0.5 * (li2(-(-2.0 * ua).exp()) + std::f64::consts::PI.powi(2) / 12.0);
+    u.signum() * i
+}
+
+/// Polynomial coefficients (of |x|, |x|^2, |x|^3, |x|^4) of the `Inflate` curve
+fn inflate_coeffs(t: f64) -> [f64; 4] {
+    let t = 20.0 * t.log10() * 5.0;
+    let a = 1.0 + (t + 50.0) / 100.0;
+    let b = -t / 50.0;
+    let c = (t - 50.0) / 100.0;
+    let d = (1.0 / 16.0) - (t / 400.0) + ((t * t) / (4.0 * 100.0 * 100.0));
+    [a, b - d, c + 2.0 * d, -d]
+}
+
 impl FunctionType {
     /// Apply the function to a value with a given parameter
     pub fn apply(&self, x: f32, t: f32) -> f32 {
@@ -95,7 +169,201 @@ impl FunctionType {
                 let b = 2f32.powf(-t);
                 b * (x / b).round()
             },
+            // Defined by the user, see `FunctionType::apply_user`
+            Custom | Expression => x,
+        }
+    }
+
+    /// Apply the function, including the functions defined by the user
+    pub fn apply_user(&self, x: f32, t: f32, user: &UserFunctions) -> f32 {
+        match self {
+            FunctionType::Custom     => t * user.curve.lookup(x / t),
+            FunctionType::Expression => user.expression.eval(x, t),
+            _ => self.apply(x, t),
+        }
+    }
+}
+
+/// Snapshot of the waveshaper parameters, taken once per sample so the same values
+/// can be used for every oversampled sample
+#[derive(Clone, Copy)]
+pub struct Shaper<'a> {
+    pub pos_type: FunctionType,
+    pub pos_param: f32,
+    pub pos_mix: f32,
+    pub neg_type: FunctionType,
+    pub neg_param: f32,
+    pub neg_mix: f32,
+    pub clip_sign: bool,
+    pub copy: TriState,
+    pub flip: bool,
+    /// Mix between the input and the shaped signal
+    pub mix: f32,
+    /// Offset added before the function and removed after it, moves the operating
+    /// point of symmetric functions so they also make even harmonics
+    pub bias: f32,
+    /// Custom curve and expression
+    pub user: &'a UserFunctions,
+}
+
+impl Shaper<'_> {
+    /// Pick the function, parameter and mix to use for a sample
+    pub fn select(&self, x: f32) -> (FunctionType, f32, f32) {
+        if match (self.copy.is_on(), self.copy.is_positive(), x >= 0.0) {
+            // If copy is on and positive is selected
+            (true,  true,  _   ) => true,
+            // If copy is on and negative is selected
+            (true,  false, _   ) => false,
+            // If copy is off and phase is positive
+            (false, _,    true ) => true,
+            // If copy is off and phase is negative
+            (false, _,    false) => false,
+        } {
+            (self.pos_type, self.pos_param, self.pos_mix)
+        } else {
+            (self.neg_type, self.neg_param, self.neg_mix)
+        }
+    }
+
+    /// Apply the waveshaper to a sample
+    pub fn apply(&self, x: f32) -> f32 {
+        self.finish(x, self.shape(x))
+    }
+
+    /// The positive/negative function mixed with the original signal, without the
+    /// clip sign, flip and mix. The output at the bias point is taken out so silence
+    /// stays silent
+    pub fn shape(&self, x: f32) -> f32 {
+        if self.bias == 0.0 {
+            return self.unbiased(x);
+        }
+        self.unbiased(x + self.bias) - self.unbiased(self.bias)
+    }
+
+    /// First antiderivative of [`Shaper::shape`]
+    pub fn antiderivative1(&self, x: f64) -> Option<f64> {
+        if self.bias == 0.0 {
+            return self.unbiased_antiderivative1(x);
+        }
+        let b = self.bias as f64;
+        Some(
+            self.unbiased_antiderivative1(x + b)? - self.unbiased_antiderivative1(b)?
+            - self.unbiased(self.bias) as f64 * x
+        )
+    }
+
+    /// Second antiderivative of [`Shaper::shape`]
+    pub fn antiderivative2(&self, x: f64) -> Option<f64> {
+        if self.bias == 0.0 {
+            return self.unbiased_antiderivative2(x);
         }
+        let b = self.bias as f64;
+        Some(
+            self.unbiased_antiderivative2(x + b)? - self.unbiased_antiderivative2(b)?
+            - self.unbiased_antiderivative1(b)? * x
+            - self.unbiased(self.bias) as f64 * x * x * 0.5
+        )
+    }
+
+    fn unbiased(&self, x: f32) -> f32 {
+        let (ft, fp, fm) = self.select(x);
+        // Mix between the original signal and the wave shaped signal
+        mix_between(x, ft.apply_user(x, fp, self.user), fm)
+    }
+
+    fn unbiased_antiderivative1(&self, x: f64) -> Option<f64> {
+        let (ft, fp, fm) = self.select(x as f32);
+        let fm = fm as f64;
+        Some((1.0 - fm) * x * x * 0.5 + fm * ft.antiderivative1(x, fp as f64)?)
+    }
+
+    fn unbiased_antiderivative2(&self, x: f64) -> Option<f64> {
+        let (ft, fp, fm) = self.select(x as f32);
+        let fm = fm as f64;
+        Some((1.0 - fm) * x * x * x / 6.0 + fm * ft.antiderivative2(x, fp as f64)?)
+    }
+
+    /// Clip sign, flip and mix an already wave shaped signal
+    pub fn finish(&self, x: f32, wss: f32) -> f32 {
+        // Clip the wave shaped signal to go out of its sign range
+        // Ex: if the wave shaped signal is positive, clip the negative part
+        let wss = if self.clip_sign {
+            if x >= 0.0 {
+                wss.max(0.0)
+            } else {
+                wss.min(0.0)
+            }
+        } else {
+            wss
+        };
+        // Flip the phase of the signal
+        let wss = if self.flip { -wss } else { wss };
+        mix_between(x, wss, self.mix)
+    }
+}
+
+impl FunctionType {
+    /// First antiderivative of the function (with F(0) = 0), used for ADAA.
+    /// Returns `None` if the function doesn't have a closed-form antiderivative
+    pub fn antiderivative1(&self, x: f64, t: f64) -> Option<f64> {
+        use FunctionType::*;
+        let xa = x.abs();
+        Some(match self {
+            // x^2 / 2 inside the threshold, t|x| - t^2 / 2 outside
+            HardClip => match xa {
+                x if x <= t => x * x * 0.5,
+                x => t * x - t * t * 0.5,
+            },
+            ScaledClip => t * HardClip.antiderivative1(x, 1.0)?,
+            // t/2 * ln(cosh(2x))
+            TwoTanh => t * 0.5 * ln_cosh(2.0 * x),
+            // 2/3 * |x|^(3/2) * t
+            Sqrt => t * (2.0 / 3.0) * xa * xa.sqrt(),
+            // 2t * (|x| - ln(1 + |x|))
+            Reciprocal => 2.0 * t * (xa - xa.ln_1p()),
+            Singlefold => match xa {
+                x if x > t => -x * x * 0.5 + 2.0 * t * x - t * t,
+                x => x * x * 0.5,
+            },
+            Inflate => {
+                let [p1, p2, p3, p4] = inflate_coeffs(t);
+                let x2 = xa * xa;
+                p1 * x2 / 2.0 + p2 * x2 * xa / 3.0 + p3 * x2 * x2 / 4.0 + p4 * x2 * x2 * xa / 5.0
+            },
+            _ => return None,
+        })
+    }
+
+    /// Second antiderivative of the function (with F(0) = 0), used for ADAA.
+    /// Returns `None` if the function doesn't have a closed-form antiderivative
+    pub fn antiderivative2(&self, x: f64, t: f64) -> Option<f64> {
+        use FunctionType::*;
+        let sig = x.signum();
+        let xa = x.abs();
+        Some(sig * match self {
+            HardClip => match xa {
+                x if x <= t => x * x * x / 6.0,
+                x => t * x * x * 0.5 - t * t * x * 0.5 + t * t * t / 6.0,
+            },
+            ScaledClip => t * HardClip.antiderivative2(xa, 1.0)?,
+            // t/4 * integral of ln(cosh(u)) from 0 to 2|x|
+            TwoTanh => t * 0.25 * ln_cosh_integral(2.0 * xa),
+            // 4/15 * |x|^(5/2) * t
+            Sqrt => t * (4.0 / 15.0) * xa * xa * xa.sqrt(),
+            // 2t * (|x|^2 / 2 - (1 + |x|) * ln(1 + |x|) + |x|)
+            Reciprocal => 2.0 * t * (xa * xa * 0.5 - (1.0 + xa) * xa.ln_1p() + xa),
+            Singlefold => match xa {
+                x if x > t => t * t * t / 3.0 - x * x * x / 6.0 + t * x * x - t * t * x,
+                x => x * x * x / 6.0,
+            },
+            Inflate => {
+                let [p1, p2, p3, p4] = inflate_coeffs(t);
+                let x3 = xa * xa * xa;
+                p1 * x3 / 6.0 + p2 * x3 * xa / 12.0 + p3 * x3 * xa * xa / 20.0
+                    + p4 * x3 * x3 / 30.0
+            },
+            _ => return None,
+        })
     }
 }
 
@@ -129,13 +397,15 @@ impl std::fmt::Display for FunctionType {
             FunctionType::Floor          => write!(f, "Floor"),
             FunctionType::Round          => write!(f, "Round"),
             FunctionType::Bitcrush       => write!(f, "Bitcrush"),
+            FunctionType::Custom         => write!(f, "Custom"),
+            FunctionType::Expression     => write!(f, "Expression"),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::FunctionType::*;
+    use super::{FunctionType::*, Shaper, TriState, UserFunctions};
 
     #[test]
     #[allow(unused_variables)]
@@ -154,4 +424,53 @@ mod tests {
         println!("Hard {}", fmt(&hard));
         println!("Test {}", fmt(&test));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn antiderivatives() {
+        let h = 1e-4;
+        for f in [HardClip, ScaledClip, TwoTanh, Sqrt, Reciprocal, Singlefold, Inflate] {
+            for t in [0.3, 1.0, 2.5] {
+                for x in (-100..100).map(|x| x as f64 * 0.037 + 0.001) {
+                    let f1 = |x| f.antiderivative1(x, t).unwrap();
+                    let f2 = |x| f.antiderivative2(x, t).unwrap();
+                    let d1 = (f1(x + h) - f1(x - h)) / (2.0 * h);
+                    let d2 = (f2(x + h) - f2(x - h)) / (2.0 * h);
+                    assert!((d1 - f.apply(x as f32, t as f32) as f64).abs() < 1e-3);
+                    assert!((d2 - f1(x)).abs() < 1e-3);
+                }
+            }
+        }
+    }
+    #[test]
+    fn biased_antiderivatives() {
+        let user = UserFunctions::default();
+        let h = 1e-4;
+        for (pos_type, neg_type) in [(HardClip, TwoTanh), (Sqrt, Singlefold), (Inflate, ScaledClip)] {
+            for bias in [-0.4, 0.25, 0.7] {
+                let shaper = Shaper {
+                    pos_type,
+                    pos_param: 0.8,
+                    pos_mix: 0.9,
+                    neg_type,
+                    neg_param: 1.5,
+                    neg_mix: 0.6,
+                    clip_sign: false,
+                    copy: TriState::Off,
+                    flip: false,
+                    mix: 1.0,
+                    bias,
+                    user: &user,
+                };
+                // ADAA1 uses the first antiderivative and ADAA2 the second one
+                let f1 = |x| shaper.antiderivative1(x).unwrap();
+                let f2 = |x| shaper.antiderivative2(x).unwrap();
+                for x in (-100..100).map(|x| x as f64 * 0.037 + 0.001) {
+                    let d1 = (f1(x + h) - f1(x - h)) / (2.0 * h);
+                    let d2 = (f2(x + h) - f2(x - h)) / (2.0 * h);
+                    assert!((d1 - shaper.shape(x as f32) as f64).abs() < 1e-3, "ADAA1 at {} with bias {}", x, bias);
+                    assert!((d2 - f1(x)).abs() < 1e-3, "ADAA2 at {} with bias {}", x, bias);
+                }
+            }
+        }
+    }
+}
@@ -1,27 +1,111 @@
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, atomic::Ordering};
 use nih_plug::prelude::*;
 
 mod params;
 mod data;
 mod editor;
 mod fxs;
+mod presets;
+mod modulation;
+mod clap;
 
 use params::PenareParams;
 use data::UIData;
+use modulation::{ModDestination, Modulation};
 use fxs::{
     filter,
-    waveshaper,
+    auto_gain::{AutoGain, AutoGainMode},
+    waveshaper::{self, UserFunctions},
+    oversample::{self, Oversampler},
+    adaa::Adaa,
+    delay::DelayLine,
+    lfo::Lfo,
+    envelope::{DetectorMode, EnvelopeFollower, EnvelopeSource},
+    adsr::Adsr,
+    crossover::{self, Crossover},
+    dc_blocker::DcBlocker,
+    meter::{ClipActivity, LevelMeter},
+    feedback::Feedback,
+    graph,
+    stereo::{self, StereoMode},
     utils::{mix_between, mix_in},
 };
 
+/// Maximum number of channels, enough for 7.1
+const MAX_CHANNELS: usize = 8;
+/// Attack and release of the input envelope the bias sags with, in milliseconds
+const BIAS_SAG_ATTACK: f32 = 5.0;
+const BIAS_SAG_RELEASE: f32 = 150.0;
+
+/// Smoothed values of the waveshaper function and filter parameters of a channel,
+/// before modulation
+#[derive(Clone, Copy)]
+struct ChannelValues {
+    pos_type: waveshaper::FunctionType,
+    pos_param: f32,
+    neg_type: waveshaper::FunctionType,
+    neg_param: f32,
+    f1_type: filter::FilterType,
+    f1_freq: f32,
+    f1_q: f32,
+    f2_type: filter::FilterType,
+    f2_freq: f32,
+    f2_q: f32,
+}
+
+/// Per-sample settings of a multiband band
+#[derive(Clone, Copy)]
+struct Band<'a> {
+    shaper: waveshaper::Shaper<'a>,
+    gain: f32,
+    level: f32,
+}
+
 struct Penare {
     params: Arc<PenareParams>,
     sample_rate: f32,
+    /// Number of main input channels, a mono input gets copied to every output
+    input_channels: usize,
     // Waveshapers Data (for the UI)
-    ui_data: Arc<Mutex<UIData>>,
+    ui_data: Arc<UIData>,
+    // Per-channel state below is sized from the audio IO layout in `initialize`
     // Filters
-    f1: [filter::Biquad; 2],
-    f2: [filter::Biquad; 2],
+    f1: Vec<filter::Biquad>,
+    f2: Vec<filter::Biquad>,
+    // Oversampling
+    oversamplers: Vec<Oversampler>,
+    // Antiderivative anti-aliasing state
+    adaa: Vec<[Adaa; waveshaper::MAX_STAGES]>,
+    // Latency compensation for the signals that aren't oversampled
+    dry_delay: Vec<DelayLine>,
+    f1_ex_delay: Vec<DelayLine>,
+    f2_ex_delay: Vec<DelayLine>,
+    // Feedback loops around the distortion
+    feedback: Vec<Feedback>,
+    dc_blockers: Vec<DcBlocker>,
+    auto_gain: AutoGain,
+    /// State of the learn parameter in the last buffer, learning starts when it
+    /// gets turned on
+    auto_gain_learn: bool,
+    // Level meters of the first two channels
+    input_meters: [LevelMeter; 2],
+    /// Level going into the waveshapers, after the pre-gain and channel drive
+    drive_meters: [LevelMeter; 2],
+    output_meters: [LevelMeter; 2],
+    clip_activity: ClipActivity,
+    // Modulation sources
+    lfos: [Lfo; 2],
+    envelope: EnvelopeFollower,
+    /// Input envelope for the bias sag
+    bias_envelope: EnvelopeFollower,
+    adsr: Adsr,
+    // Multiband
+    crossovers: Vec<Crossover>,
+    band_oversamplers: Vec<[Oversampler; crossover::MAX_BANDS]>,
+    band_adaa: Vec<[[Adaa; waveshaper::MAX_STAGES]; crossover::MAX_BANDS]>,
+    // State of each node of the patch, running at the oversampled rate
+    patch_filters: Vec<[filter::Biquad; graph::MAX_NODES]>,
+    patch_adaa: Vec<[Adaa; graph::MAX_NODES]>,
 }
 
 impl Default for Penare {
@@ -29,9 +113,32 @@ impl Default for Penare {
         Self {
             params: Arc::new(PenareParams::default()),
             sample_rate: 1.0,
-            ui_data: Arc::new(Mutex::new(UIData::default())),
-            f1: [filter::Biquad::default(); 2],
-            f2: [filter::Biquad::default(); 2],
+            input_channels: 2,
+            ui_data: Arc::new(UIData::default()),
+            f1: Vec::new(),
+            f2: Vec::new(),
+            oversamplers: Vec::new(),
+            adaa: Vec::new(),
+            dry_delay: Vec::new(),
+            f1_ex_delay: Vec::new(),
+            f2_ex_delay: Vec::new(),
+            feedback: Vec::new(),
+            dc_blockers: Vec::new(),
+            auto_gain: AutoGain::new(0, 44100.0),
+            auto_gain_learn: false,
+            input_meters: [LevelMeter::default(); 2],
+            drive_meters: [LevelMeter::default(); 2],
+            output_meters: [LevelMeter::default(); 2],
+            clip_activity: ClipActivity::new(44100.0),
+            lfos: [Lfo::new(0x9e3779b9), Lfo::new(0x85ebca6b)],
+            envelope: EnvelopeFollower::default(),
+            bias_envelope: EnvelopeFollower::default(),
+            adsr: Adsr::default(),
+            crossovers: Vec::new(),
+            band_oversamplers: Vec::new(),
+            band_adaa: Vec::new(),
+            patch_filters: Vec::new(),
+            patch_adaa: Vec::new(),
         }
     }
 }
@@ -47,17 +154,86 @@ impl Plugin for Penare {
 
     const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
-    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[AudioIOLayout {
-        main_input_channels: NonZeroU32::new(2),
-        main_output_channels: NonZeroU32::new(2),
+    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(2),
+            main_output_channels: NonZeroU32::new(2),
+
+            aux_input_ports: &[new_nonzero_u32(2)],
+            aux_output_ports: &[],
+
+            names: PortNames {
+                layout: Some("Stereo + Sidechain"),
+                aux_inputs: &["Sidechain"],
+                ..PortNames::const_default()
+            },
+        },
+        // For hosts that don't support sidechains
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(2),
+            main_output_channels: NonZeroU32::new(2),
+
+            aux_input_ports: &[],
+            aux_output_ports: &[],
+
+            names: PortNames {
+                layout: Some("Stereo"),
+                ..PortNames::const_default()
+            },
+        },
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(1),
+            main_output_channels: NonZeroU32::new(1),
 
-        aux_input_ports: &[],
-        aux_output_ports: &[],
+            aux_input_ports: &[],
+            aux_output_ports: &[],
 
-        names: PortNames::const_default(),
-    }];
+            names: PortNames {
+                layout: Some("Mono"),
+                ..PortNames::const_default()
+            },
+        },
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(1),
+            main_output_channels: NonZeroU32::new(2),
 
-    const MIDI_INPUT: MidiConfig = MidiConfig::None;
+            aux_input_ports: &[],
+            aux_output_ports: &[],
+
+            names: PortNames {
+                layout: Some("Mono to Stereo"),
+                ..PortNames::const_default()
+            },
+        },
+        // Surround, only the front left/right pair gets the stereo processing, the
+        // other channels use the main settings
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(6),
+            main_output_channels: NonZeroU32::new(6),
+
+            aux_input_ports: &[],
+            aux_output_ports: &[],
+
+            names: PortNames {
+                layout: Some("5.1"),
+                ..PortNames::const_default()
+            },
+        },
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(MAX_CHANNELS as u32),
+            main_output_channels: NonZeroU32::new(MAX_CHANNELS as u32),
+
+            aux_input_ports: &[],
+            aux_output_ports: &[],
+
+            names: PortNames {
+                layout: Some("7.1"),
+                ..PortNames::const_default()
+            },
+        },
+    ];
+
+    const MIDI_INPUT: MidiConfig = MidiConfig::Basic;
     const MIDI_OUTPUT: MidiConfig = MidiConfig::None;
 
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
@@ -79,13 +255,32 @@ impl Plugin for Penare {
 
     fn initialize(
         &mut self,
-        _audio_io_layout: &AudioIOLayout,
+        audio_io_layout: &AudioIOLayout,
         buffer_config: &BufferConfig,
-        _context: &mut impl InitContext<Self>,
+        context: &mut impl InitContext<Self>,
     ) -> bool {
         self.sample_rate = buffer_config.sample_rate;
+        self.ui_data.set_sample_rate(self.sample_rate);
+        // The custom curve and expression may have been loaded with the state
+        self.params.render_custom_curve();
+        if let Err(e) = self.params.compile_expression() {
+            nih_error!("Failed to compile the expression: {}", e);
+        }
+        self.params.compile_patch();
+
+        let channels = |n: Option<NonZeroU32>| n.map_or(0, NonZeroU32::get) as usize;
+        self.input_channels = channels(audio_io_layout.main_input_channels);
+        self.resize_channels(channels(audio_io_layout.main_output_channels));
+
+        self.update_oversampling();
+        context.set_latency_samples(self.oversamplers[0].latency());
 
         self.update_ui_data();
+        self.bias_envelope.set_times(BIAS_SAG_ATTACK, BIAS_SAG_RELEASE, self.sample_rate);
+        self.input_meters = [LevelMeter::new(self.sample_rate); 2];
+        self.drive_meters = [LevelMeter::new(self.sample_rate); 2];
+        self.output_meters = [LevelMeter::new(self.sample_rate); 2];
+        self.clip_activity = ClipActivity::new(self.sample_rate);
 
         for filter in &mut self.f1 {
             filter.sample_rate = self.sample_rate;
@@ -93,8 +288,8 @@ impl Plugin for Penare {
         for filter in &mut self.f2 {
             filter.sample_rate = self.sample_rate;
         }
-        self.update_f1();
-        self.update_f2();
+        let values = self.channel_values();
+        self.update_fs(&[Modulation::default(); 2], &values);
 
         true
     }
@@ -106,141 +301,684 @@ impl Plugin for Penare {
         for filter in &mut self.f2 {
             filter.reset();
         }
+        for oversampler in &mut self.oversamplers {
+            oversampler.reset();
+        }
+        for adaa in self.adaa.iter_mut().flatten() {
+            adaa.reset();
+        }
+        for lfo in &mut self.lfos {
+            lfo.reset();
+        }
+        self.envelope.reset();
+        self.bias_envelope.reset();
+        self.adsr.reset();
+        for crossover in &mut self.crossovers {
+            crossover.reset();
+        }
+        for oversampler in self.band_oversamplers.iter_mut().flatten() {
+            oversampler.reset();
+        }
+        for adaa in self.band_adaa.iter_mut().flatten().flatten() {
+            adaa.reset();
+        }
+        for filter in self.patch_filters.iter_mut().flatten() {
+            filter.reset();
+        }
+        for adaa in self.patch_adaa.iter_mut().flatten() {
+            adaa.reset();
+        }
+        for delay in self.dry_delay.iter_mut()
+            .chain(self.f1_ex_delay.iter_mut())
+            .chain(self.f2_ex_delay.iter_mut())
+        {
+            delay.reset();
+        }
+        for feedback in &mut self.feedback {
+            feedback.reset();
+        }
+        for dc_blocker in &mut self.dc_blockers {
+            dc_blocker.reset();
+        }
+        self.auto_gain.reset();
+        for meter in self.input_meters.iter_mut()
+            .chain(&mut self.drive_meters)
+            .chain(&mut self.output_meters)
+        {
+            meter.reset();
+        }
+        self.clip_activity.reset();
     }
 
     fn process(
         &mut self,
         buffer: &mut Buffer,
-        _aux: &mut AuxiliaryBuffers,
-        _context: &mut impl ProcessContext<Self>,
+        aux: &mut AuxiliaryBuffers,
+        context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        for channel_samples in buffer.iter_samples() {
-            self.update_fs();
+        if self.oversamplers[0].factor() != self.params.oversampling.value()
+            || self.oversamplers[0].inner_delay() != self.adaa_delay()
+        {
+            self.update_oversampling();
+            context.set_latency_samples(self.oversamplers[0].latency());
+        }
+
+        let transport = context.transport();
+        let tempo = transport.tempo.unwrap_or(120.0);
+        let pos_beats = if transport.playing { transport.pos_beats() } else { None };
+        self.sync_lfos(pos_beats);
+        self.envelope.set_times(
+            self.params.env.attack.value(),
+            self.params.env.release.value(),
+            self.sample_rate,
+        );
+
+        // Without a sidechain connected the envelope follower in sidechain mode stays
+        // at zero
+        let sidechain = aux.inputs.first().map(|sc| sc.as_slice_immutable());
+
+        let crossover_freqs = self.params.crossovers().map(|freq| freq.value());
+        for crossover in &mut self.crossovers {
+            crossover.set_frequencies(crossover_freqs, self.sample_rate);
+        }
+        // Keep our own reference so the waveshapers can borrow the user functions
+        // while the rest of the state changes
+        let user_functions = self.params.user_functions.clone();
+        let patch = self.params.patch_slot.clone();
+        let patch_mode = self.params.patch_mode.value();
+        let stage_count = self.params.stage_count.value() as usize;
+        let multiband = self.params.multiband.value();
+        let band_count = self.params.band_count.value() as usize;
+        let stereo_mode = self.params.stereo_mode.value();
+        // Mid/side needs a pair of channels
+        let mid_side = stereo_mode == StereoMode::MidSide && buffer.channels() >= 2;
+        let dc_block = self.params.dc_block.value();
+        let auto_gain_mode = self.params.auto_gain.value();
+        let loudness = self.params.auto_gain_loudness.value();
+        let learn = self.params.auto_gain_learn.value();
+        if learn && !self.auto_gain_learn {
+            self.auto_gain.start_learning();
+        }
+        self.auto_gain_learn = learn;
+
+        let mut next_event = context.next_event();
+        for (sample_idx, mut channel_samples) in buffer.iter_samples().enumerate() {
+            // Trigger the ADSR with the notes up to this sample
+            while let Some(event) = next_event {
+                if event.timing() > sample_idx as u32 {
+                    break;
+                }
+                match event {
+                    NoteEvent::NoteOn { note, velocity, .. } => {
+                        let amount = self.params.adsr.velocity.value();
+                        self.adsr.note_on(note, 1.0 - amount * (1.0 - velocity));
+                    },
+                    NoteEvent::NoteOff { note, .. } => self.adsr.note_off(note),
+                    _ => (),
+                }
+                next_event = context.next_event();
+            }
+
+            let env_mode = self.params.env.mode.value();
+            let level = match self.params.env.source.value() {
+                EnvelopeSource::Input     => Self::level(channel_samples.iter_mut().map(|s| *s), env_mode),
+                EnvelopeSource::Sidechain => match sidechain {
+                    Some(sc) => Self::level(sc.iter().map(|channel| channel[sample_idx]), env_mode),
+                    None     => 0.0,
+                },
+            };
+            let mods = self.next_modulation(tempo, level);
+            let input_level = Self::level(channel_samples.iter_mut().map(|s| *s), DetectorMode::Peak);
+            let sag = self.bias_envelope.process(input_level, DetectorMode::Peak).min(1.0);
+            let channel_mods = self.channel_modulation(&mods);
+            let channel_values = self.channel_values();
+            self.update_fs(&channel_mods, &channel_values);
 
             //   Input
+            //     │
+            // M/S Encode (Mid/Side mode only)
+            //     │
             //     ├───────────────┐
             //     │               ├─(Dry Signal)
             //   Filter ───────┐   │
             //     │           │   │
-            //  Pre-Gain       ├─(Excess Signal)
-            //     │           │   │
-            // Distortions     │   │
-            //     │           │   │
-            // Post-Gain       │   │
+            //     + ◄─────────┼───┼─────┐
+            //     │           │   │     │
+            //  Pre-Gain ┐     ├─(Excess Signal)
+            //     │     │     │   │     │
+            // Distortions (Oversampled, up to 4 in series)
+            //     │     │     │   │  Feedback (Delay, Damping, Limiter)
+            // Post-Gain ┘     │   │     │
+            //     │           │   │     │
+            // DC Blocker      │   │     │
+            //     ├───────────┼───┼─────┘
             //     │           │   │
             // Excess Mix ─────┘   │
             //     │               │
+            // Auto Gain           │
+            //     │               │
             //    Mix ─────────────┘
             //     │
             // Final-Clip
             //     │
+            // M/S Decode (Mid/Side mode only)
+            //     │
             //   Output
+            //
+            // In patch mode the patch replaces the filter, pre-gain, distortion,
+            // post-gain and excess mix, and runs oversampled as a whole
+
+            let input_gain = self.modulated(&mods, ModDestination::InputGain, &self.params.input_gain);
+            let output_gain = self.modulated(&mods, ModDestination::OutputGain, &self.params.output_gain);
+            let shapers = self.shapers(&user_functions, &channel_mods, &channel_values, sag);
+            let stages = self.stages(&user_functions);
+            let quality = self.params.quality.value();
+            let bands = if multiband { self.bands(&shapers, &channel_mods) } else { [[None; crossover::MAX_BANDS]; 2] };
+            let excess_mix = self.modulated(&mods, ModDestination::ExcessMix, &self.params.excess_mix);
+            let mix = self.modulated(&mods, ModDestination::Mix, &self.params.mix);
+            let feedback = self.params.feedback.smoothed.next();
+            let feedback_delay = (self.params.feedback_delay.smoothed.next() * 0.001 * self.sample_rate).round() as usize;
+            let feedback_damping = self.params.feedback_damping.smoothed.next();
+            let compensation = match auto_gain_mode {
+                AutoGainMode::Off        => 1.0,
+                AutoGainMode::Continuous => self.auto_gain.gain(),
+                AutoGainMode::Learned    => self.params.learned_gain.load(Ordering::Relaxed),
+            };
+            let clip_threshold = self.modulated(
+                &mods,
+                ModDestination::OutputClipThreshold,
+                &self.params.output_clip_threshold,
+            );
+
+            // Per-channel drive and mix, channel 1 is left or mid and channel 2 is
+            // right or side
+            let channel_drives = self.params.channels.each_ref().map(|c| c.drive.smoothed.next());
+            let channel_mixes = self.params.channels.each_ref().map(|c| c.mix.smoothed.next());
 
-            let mut samples: [f32; 2] = [0.0, 0.0];
-            for (channel_idx, sample) in channel_samples.into_iter().enumerate() {
-                let dry = *sample;
+            let channels = channel_samples.len().min(MAX_CHANNELS);
+            let mut samples = [0.0; MAX_CHANNELS];
+            for (s, sample) in samples.iter_mut().zip(channel_samples.iter_mut()) {
+                *s = *sample;
+            }
+            // The host only fills the first channel with a mono input
+            if self.input_channels == 1 {
+                let mono = samples[0];
+                samples[1..channels].fill(mono);
+            }
+            // A single channel shows on both sides of the meters
+            let meter_channels = [0, 1.min(channels - 1)];
+            for (meter, channel) in self.input_meters.iter_mut().zip(meter_channels) {
+                meter.process(samples[channel]);
+            }
+            if mid_side {
+                [samples[0], samples[1]] = stereo::encode([samples[0], samples[1]]);
+            }
+
+            let mut dry_samples = [0.0; MAX_CHANNELS];
+            let mut wet_samples = [0.0; MAX_CHANNELS];
+            let mut drive_samples = [0.0; MAX_CHANNELS];
+            let mut clipped = 0;
+            let mut overshoot: f32 = 0.0;
+            for (channel_idx, sample) in samples.iter_mut().enumerate().take(channels) {
+                // Only the first pair of channels is processed as left/right or
+                // mid/side, the other channels use the main settings
+                let pair_idx = if channel_idx < 2 { channel_idx } else { 0 };
+                let (drive, channel_mix) = if stereo_mode == StereoMode::Linked || channel_idx >= 2 {
+                    (1.0, 1.0)
+                } else {
+                    (channel_drives[pair_idx], channel_mixes[pair_idx])
+                };
+                let input_gain = input_gain * drive;
+
+                // Delay the signals that skip the oversampled section so they stay
+                // aligned with the wet signal
+                let dry = self.dry_delay[channel_idx].process(*sample);
+                dry_samples[channel_idx] = dry;
                 // --- Filter ---
                 // Apply low-pass filter
                 let (s, f1_ex) = self.f1_process(channel_idx, *sample);
                 // Apply high-pass filter
                 let (s, f2_ex) = self.f2_process(channel_idx, s);
-                *sample = s;
+                let f1_ex = self.f1_ex_delay[channel_idx].process(f1_ex);
+                let f2_ex = self.f2_ex_delay[channel_idx].process(f2_ex);
+                // --- Feedback ---
+                let fb = feedback * self.feedback[channel_idx].next(feedback_delay, feedback_damping, self.sample_rate);
+                let s = s + fb;
+                // What the waveshapers get, the patch starts from the unfiltered input
+                drive_samples[channel_idx] = if patch_mode { *sample + fb } else { s } * input_gain;
 
-                // --- Pre-Gain ---
-                *sample *= self.params.input_gain.smoothed.next();
-
-                // --- Distortions ---
-
-                // - Waveshaper
-                let should_copy = self.params.copy_function.value();
-                // Wave shaped signal
-                let (ft, fp, fm) = if match (should_copy.is_on(), should_copy.is_positive(), *sample >= 0.0) {
-                    // If copy is on and positive is selected
-                    (true,  true,  _   ) => true,
-                    // If copy is on and negative is selected
-                    (true,  false, _   ) => false,
-                    // If copy is off and phase is positive
-                    (false, _,    true ) => true,
-                    // If copy is off and phase is negative
-                    (false, _,    false) => false,
-                } {
-                    (self.params.pos_function_type.value(),
-                    self.params.pos_function_param.smoothed.next(),
-                    self.params.pos_function_mix.smoothed.next())
+                *sample = if patch_mode {
+                    let (f1, f2) = (self.f1[channel_idx], self.f2[channel_idx]);
+                    let shaper = &shapers[pair_idx];
+                    let filters = &mut self.patch_filters[channel_idx];
+                    let adaa = &mut self.patch_adaa[channel_idx];
+                    self.oversamplers[channel_idx].process(*sample + fb, |s| {
+                        patch.run(s, |node, slot, s| {
+                            use graph::NodeKind::*;
+                            // The patch filters follow the (modulated) main filters
+                            let filter = &mut filters[slot];
+                            match node {
+                                Filter1 | Excess1 => update_filter(filter, f1.filter_type, f1.freq, f1.q),
+                                Filter2 | Excess2 => update_filter(filter, f2.filter_type, f2.freq, f2.q),
+                                _ => (),
+                            }
+                            match node {
+                                Filter1 | Filter2 => filter.process(s).0,
+                                Excess1 | Excess2 => filter.process(s).1,
+                                Shaper(0)         => adaa[slot].process(s, shaper, quality),
+                                Shaper(i)         => adaa[slot].process(s, &stages[(i - 1).min(stages.len() - 1)], quality),
+                                PreGain           => s * input_gain,
+                                PostGain          => s * output_gain,
+                            }
+                        })
+                    })
+                } else if multiband {
+                    // Every band gets its own pre-gain, waveshaper and level, the
+                    // extra stages run after each band's waveshaper
+                    let split = self.crossovers[channel_idx].process(s, band_count);
+                    let mut sum = 0.0;
+                    for (band_idx, band) in bands[pair_idx].iter().enumerate().take(band_count) {
+                        let Some(band) = band else { continue };
+                        let [adaa, stage_adaa @ ..] = &mut self.band_adaa[channel_idx][band_idx];
+                        sum += self.band_oversamplers[channel_idx][band_idx].process(split[band_idx], |s| {
+                            let s = s * input_gain * band.gain;
+                            let mut s = adaa.process(s, &band.shaper, quality);
+                            for (adaa, stage) in stage_adaa.iter_mut().zip(&stages).take(stage_count - 1) {
+                                s = adaa.process(s, stage, quality);
+                            }
+                            s * band.level
+                        });
+                    }
+                    sum * output_gain
                 } else {
-                    (self.params.neg_function_type.value(),
-                    self.params.neg_function_param.smoothed.next(),
-                    self.params.neg_function_mix.smoothed.next())
+                    let shaper = &shapers[pair_idx];
+                    let [adaa, stage_adaa @ ..] = &mut self.adaa[channel_idx];
+                    self.oversamplers[channel_idx].process(s, |s| {
+                        // --- Pre-Gain ---
+                        let s = s * input_gain;
+                        // --- Distortions ---
+                        let mut s = adaa.process(s, shaper, quality);
+                        for (adaa, stage) in stage_adaa.iter_mut().zip(&stages).take(stage_count - 1) {
+                            s = adaa.process(s, stage, quality);
+                        }
+                        // --- Post-Gain ---
+                        s * output_gain
+                    })
                 };
-                // Mix between the original signal and the wave shaped signal
-                let wss = mix_between(
-                    *sample,
-                    ft.apply(*sample, fp),
-                    fm,
-                );
-                // Clip the wave shaped signal to go out of its sign range
-                // Ex: if the wave shaped signal is positive, clip the negative part
-                let wss = if self.params.clip_sign.value() {
-                    if *sample >= 0.0 {
-                        wss.max(0.0)
+                // --- DC Blocker ---
+                // Keeps running while off so the offset can still be shown
+                let blocked = self.dc_blockers[channel_idx].process(*sample, self.sample_rate);
+                if dc_block {
+                    *sample = blocked;
+                }
+                self.feedback[channel_idx].push(*sample);
+
+                // Filter mix, the patch routes the excess signals itself
+                if !patch_mode {
+                    if !self.params.excess_bypass.value() {
+                        // Mix in excess signal
+                        *sample = mix_in(
+                            *sample,
+                            excess_mix * f1_ex
+                            + excess_mix * f2_ex,
+                            excess_mix,
+                        );
                     } else {
-                        wss.min(0.0)
+                        // Excess signal only
+                        *sample = f1_ex + f2_ex;
                     }
-                } else {
-                    wss
-                };
-                // Flip the phase of the signal
-                let wss = if self.params.flip.value() { -wss } else { wss };
-                *sample = mix_between(*sample, wss, self.params.function_mix.smoothed.next());
-
-                // --- Post-Gain ---
-                *sample *= self.params.output_gain.smoothed.next(); // Post-gain
-
-                // Filter mix
-                if !self.params.excess_bypass.value() {
-                    // Mix in excess signal
-                    let excess_mix = self.params.excess_mix.smoothed.next();
-                    *sample = mix_in(
-                        *sample,
-                        excess_mix * f1_ex
-                        + excess_mix * f2_ex,
-                        excess_mix,
-                    );
-                } else {
-                    // Excess signal only
-                    *sample = f1_ex + f2_ex;
                 }
 
+                // --- Auto Gain ---
+                wet_samples[channel_idx] = *sample;
+                *sample *= compensation;
+
                 // Mix between dry and wet
-                *sample = mix_between(dry, *sample, self.params.mix.smoothed.next());
+                *sample = mix_between(dry, *sample, mix * channel_mix);
 
                 // Final clip
                 if self.params.output_clip.value() {
+                    let ratio = sample.abs() / clip_threshold;
+                    if ratio > 1.0 {
+                        clipped += 1;
+                        overshoot = overshoot.max(ratio);
+                    }
                     *sample = waveshaper::FunctionType::HardClip.apply(
                         *sample,
-                        self.params.output_clip_threshold.smoothed.next(),
+                        clip_threshold,
                     );
                 }
+            }
 
-                if channel_idx == 0 || channel_idx == 1 {
-                    samples[channel_idx] = *sample;
+            // Measure the loudness without the compensation
+            if auto_gain_mode != AutoGainMode::Off || self.auto_gain.is_learning() {
+                self.auto_gain.measure(&dry_samples[..channels], &wet_samples[..channels], loudness);
+                if let Some(gain) = self.auto_gain.learned() {
+                    self.params.learned_gain.store(gain, Ordering::Relaxed);
                 }
             }
 
+            if mid_side {
+                [samples[0], samples[1]] = stereo::decode([samples[0], samples[1]]);
+                [dry_samples[0], dry_samples[1]] = stereo::decode([dry_samples[0], dry_samples[1]]);
+            }
+            for (sample, s) in channel_samples.iter_mut().zip(samples) {
+                *sample = s;
+            }
+            for (meter, channel) in self.drive_meters.iter_mut().zip(meter_channels) {
+                meter.process(drive_samples[channel]);
+            }
+            for (meter, channel) in self.output_meters.iter_mut().zip(meter_channels) {
+                meter.process(samples[channel]);
+            }
+            self.clip_activity.process(channels, clipped, overshoot);
+            if clipped > 0 {
+                self.ui_data.output_clipped.store(true, Ordering::Relaxed);
+            }
+
             // Only calculate the UI-related data if the editor is open.
             if self.params.editor_state.is_open() {
                 self.update_ui_data();
+                let mean = |samples: &[f32]| samples.iter().sum::<f32>() / channels.max(1) as f32;
+                self.ui_data.waveform.push(
+                    mean(&dry_samples[..channels]),
+                    mean(&samples[..channels]),
+                );
+                let dc_offset = self.dc_blockers.iter()
+                    .map(|blocker| blocker.offset())
+                    .fold(0.0, |max: f32, offset| if offset.abs() > max.abs() { offset } else { max });
+                self.ui_data.set_dc_offset(dc_offset);
+                self.ui_data.set_auto_gain(compensation);
+                self.ui_data.set_auto_gain_learn_state(self.auto_gain.learn_state());
+                self.ui_data.input_levels.set(
+                    self.input_meters.map(|meter| meter.peak()),
+                    self.input_meters.map(|meter| meter.rms()),
+                );
+                self.ui_data.drive_levels.set(
+                    self.drive_meters.map(|meter| meter.peak()),
+                    self.drive_meters.map(|meter| meter.rms()),
+                );
+                self.ui_data.output_levels.set(
+                    self.output_meters.map(|meter| meter.peak()),
+                    self.output_meters.map(|meter| meter.rms()),
+                );
+                self.ui_data.set_clip_ratio(self.clip_activity.clipped_ratio());
+                self.ui_data.set_clip_overshoot(self.clip_activity.max_overshoot());
             }
         }
+        if self.params.editor_state.is_open() {
+            self.ui_data.publish_frame();
+        }
 
         ProcessStatus::Normal
     }
 }
 
 impl Penare {
+    /// Take a snapshot of the waveshaper parameters of each channel for the current
+    /// sample. `sag` is the input envelope the bias drops with
+    fn shapers<'a>(
+        &self,
+        user: &'a UserFunctions,
+        mods: &[Modulation; 2],
+        values: &[ChannelValues; 2],
+        sag: f32,
+    ) -> [waveshaper::Shaper<'a>; 2] {
+        use ModDestination::*;
+        let params = &self.params;
+        let pos_mix = params.pos_function_mix.smoothed.next();
+        let neg_mix = params.neg_function_mix.smoothed.next();
+        let mix = params.function_mix.smoothed.next();
+        let bias = params.bias.smoothed.next() * (1.0 - params.bias_sag.smoothed.next() * sag);
+        std::array::from_fn(|channel| {
+            let (mods, values) = (&mods[channel], &values[channel]);
+            waveshaper::Shaper {
+                pos_type: values.pos_type,
+                pos_param: mods.apply(PosFunctionParam, &params.pos_function_param, values.pos_param),
+                pos_mix: mods.apply(PosFunctionMix, &params.pos_function_mix, pos_mix),
+                neg_type: values.neg_type,
+                neg_param: mods.apply(NegFunctionParam, &params.neg_function_param, values.neg_param),
+                neg_mix: mods.apply(NegFunctionMix, &params.neg_function_mix, neg_mix),
+                clip_sign: params.clip_sign.value(),
+                copy: params.copy_function.value(),
+                flip: params.flip.value(),
+                mix: mods.apply(FunctionMix, &params.function_mix, mix),
+                bias: mods.apply(Bias, &params.bias, bias),
+                user,
+            }
+        })
+    }
+
+    /// Take a snapshot of the waveshapers after the main one, they are shared by all
+    /// channels and not modulated
+    fn stages<'a>(&self, user: &'a UserFunctions) -> [waveshaper::Shaper<'a>; waveshaper::MAX_STAGES - 1] {
+        self.params.stages().map(|params| waveshaper::Shaper {
+            pos_type: params.pos_function_type.value(),
+            pos_param: params.pos_function_param.smoothed.next(),
+            pos_mix: params.pos_function_mix.smoothed.next(),
+            neg_type: params.neg_function_type.value(),
+            neg_param: params.neg_function_param.smoothed.next(),
+            neg_mix: params.neg_function_mix.smoothed.next(),
+            clip_sign: params.clip_sign.value(),
+            copy: params.copy_function.value(),
+            flip: params.flip.value(),
+            mix: params.function_mix.smoothed.next(),
+            bias: 0.0,
+            user,
+        })
+    }
+
+    /// Get the waveshaper function and filter values of each channel for the current
+    /// sample. Linked channels share the main parameters, otherwise the right/side
+    /// channel uses its own
+    fn channel_values(&self) -> [ChannelValues; 2] {
+        let params = &self.params;
+        let main = ChannelValues {
+            pos_type: params.pos_function_type.value(),
+            pos_param: params.pos_function_param.smoothed.next(),
+            neg_type: params.neg_function_type.value(),
+            neg_param: params.neg_function_param.smoothed.next(),
+            f1_type: params.f1_type.value(),
+            f1_freq: params.f1_freq.smoothed.next(),
+            f1_q: params.f1_q.smoothed.next(),
+            f2_type: params.f2_type.value(),
+            f2_freq: params.f2_freq.smoothed.next(),
+            f2_q: params.f2_q.smoothed.next(),
+        };
+        if params.stereo_link.value() {
+            return [main, main];
+        }
+        let unlinked = &params.unlinked;
+        [main, ChannelValues {
+            pos_type: unlinked.pos_function_type.value(),
+            pos_param: unlinked.pos_function_param.smoothed.next(),
+            neg_type: unlinked.neg_function_type.value(),
+            neg_param: unlinked.neg_function_param.smoothed.next(),
+            f1_type: unlinked.f1_type.value(),
+            f1_freq: unlinked.f1_freq.smoothed.next(),
+            f1_q: unlinked.f1_q.smoothed.next(),
+            f2_type: unlinked.f2_type.value(),
+            f2_freq: unlinked.f2_freq.smoothed.next(),
+            f2_q: unlinked.f2_q.smoothed.next(),
+        }]
+    }
+
+    /// Modulation of each channel, the stereo offset pushes the function parameters
+    /// and filter frequencies down on the first channel and up on the second
+    fn channel_modulation(&self, mods: &Modulation) -> [Modulation; 2] {
+        use ModDestination::*;
+        let spread = self.params.stereo_offset.smoothed.next() * 0.5;
+        [-spread, spread].map(|offset| {
+            let mut mods = *mods;
+            for destination in [PosFunctionParam, NegFunctionParam, F1Freq, F2Freq] {
+                mods.add(destination, offset);
+            }
+            mods
+        })
+    }
+
+    /// Settings of every multiband band of each channel for the current sample, `None`
+    /// for bands that are muted or not soloed. The band waveshapers share the clip
+    /// sign, copy, flip and bias settings with the channel's main waveshaper, and the
+    /// channel's function parameter modulation (like the stereo offset) applies to
+    /// the band parameters too
+    fn bands<'a>(
+        &self,
+        shapers: &[waveshaper::Shaper<'a>; 2],
+        mods: &[Modulation; 2],
+    ) -> [[Option<Band<'a>>; crossover::MAX_BANDS]; 2] {
+        use ModDestination::*;
+        let any_solo = self.params.bands.iter().any(|band| band.solo.value());
+        let mut bands = [[None; crossover::MAX_BANDS]; 2];
+        for (i, params) in self.params.bands.iter().enumerate() {
+            // Advance the smoothers once per sample, whatever channels use them
+            let pos_param = params.pos_function_param.smoothed.next();
+            let neg_param = params.neg_function_param.smoothed.next();
+            let mix = params.mix.smoothed.next();
+            let gain = params.gain.smoothed.next();
+            let level = params.level.smoothed.next();
+            if params.mute.value() || (any_solo && !params.solo.value()) {
+                continue;
+            }
+            for (channel, (main, mods)) in shapers.iter().zip(mods).enumerate() {
+                bands[channel][i] = Some(Band {
+                    shaper: waveshaper::Shaper {
+                        pos_type: params.pos_function_type.value(),
+                        pos_param: mods.apply(PosFunctionParam, &params.pos_function_param, pos_param),
+                        pos_mix: 1.0,
+                        neg_type: params.neg_function_type.value(),
+                        neg_param: mods.apply(NegFunctionParam, &params.neg_function_param, neg_param),
+                        neg_mix: 1.0,
+                        mix,
+                        ..*main
+                    },
+                    gain,
+                    level,
+                });
+            }
+        }
+        bands
+    }
+
+    /// Get the next smoothed value of a parameter with modulation applied
+    fn modulated(&self, mods: &Modulation, destination: ModDestination, param: &FloatParam) -> f32 {
+        mods.apply(destination, param, param.smoothed.next())
+    }
+
+    /// Follow the host's transport with the tempo-synced LFOs
+    fn sync_lfos(&mut self, pos_beats: Option<f64>) {
+        let Some(pos_beats) = pos_beats else { return };
+        for (lfo, params) in self.lfos.iter_mut().zip(self.params.lfos()) {
+            if params.sync.value() {
+                lfo.set_phase((pos_beats / params.sync_rate.value().beats()) as f32);
+            }
+        }
+    }
+
+    /// Level of a frame for the envelope follower, the loudest channel in peak mode
+    /// and the mean over channels in RMS mode
+    fn level(samples: impl Iterator<Item = f32>, mode: DetectorMode) -> f32 {
+        match mode {
+            DetectorMode::Peak => samples.fold(0.0, |max, s| max.max(s.abs())),
+            DetectorMode::Rms  => {
+                let (sum, count) = samples.fold((0.0, 0), |(sum, count), s| (sum + s * s, count + 1));
+                (sum / count.max(1) as f32).sqrt()
+            },
+        }
+    }
+
+    /// Advance the modulation sources by a sample and sum them up. `level` is the
+    /// input level for the envelope follower
+    fn next_modulation(&mut self, tempo: f64, level: f32) -> Modulation {
+        let mut mods = Modulation::default();
+        for (lfo, params) in self.lfos.iter_mut().zip(self.params.lfos()) {
+            let increment = if params.sync.value() {
+                (tempo / 60.0 / params.sync_rate.value().beats()) as f32 / self.sample_rate
+            } else {
+                params.rate.value() / self.sample_rate
+            };
+            let value = lfo.next(params.shape.value(), increment);
+            mods.add(params.destination.value(), value * params.depth.smoothed.next());
+        }
+
+        let env = self.envelope.process(level, self.params.env.mode.value());
+        mods.add(self.params.env.destination.value(), env.min(1.0) * self.params.env.amount.smoothed.next());
+
+        let adsr = &self.params.adsr;
+        let env = self.adsr.next(
+            adsr.attack.value(),
+            adsr.decay.value(),
+            adsr.sustain.value(),
+            adsr.release.value(),
+            self.sample_rate,
+        );
+        mods.add(adsr.destination.value(), env * adsr.amount.smoothed.next());
+        mods
+    }
+
+    /// Size the per-channel state for the number of channels
+    fn resize_channels(&mut self, channels: usize) {
+        let channels = channels.min(MAX_CHANNELS);
+        self.f1 = vec![filter::Biquad::default(); channels];
+        self.f2 = vec![filter::Biquad::default(); channels];
+        self.oversamplers = vec![Oversampler::default(); channels];
+        self.adaa = vec![[Adaa::default(); waveshaper::MAX_STAGES]; channels];
+        let delays = || (0..channels).map(|_| DelayLine::new(oversample::MAX_LATENCY)).collect();
+        self.dry_delay = delays();
+        self.f1_ex_delay = delays();
+        self.f2_ex_delay = delays();
+        self.feedback = vec![Feedback::new(self.sample_rate); channels];
+        self.dc_blockers = vec![DcBlocker::default(); channels];
+        self.auto_gain = AutoGain::new(channels, self.sample_rate);
+        self.crossovers = vec![Crossover::default(); channels];
+        self.band_oversamplers = vec![[Oversampler::default(); crossover::MAX_BANDS]; channels];
+        self.band_adaa = vec![[[Adaa::default(); waveshaper::MAX_STAGES]; crossover::MAX_BANDS]; channels];
+        self.patch_filters = vec![[filter::Biquad::default(); graph::MAX_NODES]; channels];
+        self.patch_adaa = vec![[Adaa::default(); graph::MAX_NODES]; channels];
+    }
+
+    /// Delay the ADAA adds to the oversampled section, in whole oversampled samples.
+    /// ADAA1 delays every waveshaper by half a sample, with an odd number of them the
+    /// last half sample isn't compensated. It's a small fraction of a sample once
+    /// oversampled, and without oversampling it only softens the top octave when
+    /// mixing with the dry signal. In patch mode the longest chain of waveshapers is
+    /// compensated, parallel branches with fewer waveshapers stay a bit early
+    fn adaa_delay(&self) -> usize {
+        let shapers = if self.params.patch_mode.value() {
+            self.params.patch_slot.shaper_depth()
+        } else {
+            self.params.stage_count.value() as usize
+        };
+        shapers * self.params.quality.value().half_sample_delay() / 2
+    }
+
+    /// Apply the oversampling factor and delay the dry and excess signals by the
+    /// resulting latency, which includes the delay of the ADAA
+    fn update_oversampling(&mut self) {
+        let factor = self.params.oversampling.value();
+        let adaa_delay = self.adaa_delay();
+        for oversampler in self.oversamplers.iter_mut()
+            .chain(self.band_oversamplers.iter_mut().flatten())
+        {
+            oversampler.set_factor(factor, adaa_delay);
+        }
+        for filter in self.patch_filters.iter_mut().flatten() {
+            filter.sample_rate = self.sample_rate * factor.ratio() as f32;
+            filter.calculate_coeff();
+        }
+        let latency = self.oversamplers[0].latency() as usize;
+        for delay in self.dry_delay.iter_mut()
+            .chain(self.f1_ex_delay.iter_mut())
+            .chain(self.f2_ex_delay.iter_mut())
+        {
+            delay.set_delay(latency);
+            delay.reset();
+        }
+    }
+
     /// Update waveshapers data to be sent to the UI
     fn update_ui_data(&mut self) {
-        let ui_data = self.ui_data.lock().unwrap();
+        let ui_data = &self.ui_data;
         ui_data.set_mix(self.params.function_mix.smoothed.next());
         ui_data.set_input_gain(self.params.input_gain.smoothed.next());
         ui_data.set_output_gain(self.params.output_gain.smoothed.next());
@@ -261,19 +999,27 @@ impl Penare {
         ui_data.set_clip_sign(self.params.clip_sign.value());
         ui_data.set_copy(self.params.copy_function.value());
         ui_data.set_flip(self.params.flip.value());
+        ui_data.set_bias(self.params.bias.value());
     }
 
     /// Update filters (when parameters change)
-    fn update_fs(&mut self) {
-        if self.params.f1_freq.smoothed.is_smoothing()
-        || self.params.f1_q.smoothed.is_smoothing()
-        || self.f1[0].filter_type != self.params.f1_type.value() {
-            self.update_f1();
-        }
-        if self.params.f2_freq.smoothed.is_smoothing()
-        || self.params.f2_q.smoothed.is_smoothing()
-        || self.f2[0].filter_type != self.params.f2_type.value() {
-            self.update_f2();
+    fn update_fs(&mut self, mods: &[Modulation; 2], values: &[ChannelValues; 2]) {
+        use ModDestination::*;
+        let settings: [_; 2] = std::array::from_fn(|i| {
+            let (mods, values) = (&mods[i], &values[i]);
+            (
+                mods.apply(F1Freq, &self.params.f1_freq, values.f1_freq),
+                mods.apply(F1Q, &self.params.f1_q, values.f1_q),
+                mods.apply(F2Freq, &self.params.f2_freq, values.f2_freq),
+                mods.apply(F2Q, &self.params.f2_q, values.f2_q),
+            )
+        });
+        // Channels past the first pair use the main settings
+        for (channel_idx, (f1, f2)) in self.f1.iter_mut().zip(&mut self.f2).enumerate() {
+            let pair_idx = if channel_idx < 2 { channel_idx } else { 0 };
+            let (f1_freq, f1_q, f2_freq, f2_q) = settings[pair_idx];
+            update_filter(f1, values[pair_idx].f1_type, f1_freq, f1_q);
+            update_filter(f2, values[pair_idx].f2_type, f2_freq, f2_q);
         }
     }
 
@@ -286,31 +1032,15 @@ impl Penare {
     fn f2_process(&mut self, channel_index: usize, sample: f32) -> (f32, f32) {
         self.f2[channel_index].process(sample)
     }
+}
 
-    /// Update first filter with current parameters
-    fn update_f1(&mut self) {
-        let ty = self.params.f1_type.value();
-        let freq = self.params.f1_freq.smoothed.next();
-        let q = self.params.f1_q.smoothed.next();
-        for filter in &mut self.f1 {
-            filter.filter_type = ty;
-            filter.freq = freq;
-            filter.q = q;
-            filter.calculate_coeff();
-        }
-    }
-
-    /// Update second filter with current parameters
-    fn update_f2(&mut self) {
-        let ty = self.params.f2_type.value();
-        let freq = self.params.f2_freq.smoothed.next();
-        let q = self.params.f2_q.smoothed.next();
-        for filter in &mut self.f2 {
-            filter.filter_type = ty;
-            filter.freq = freq;
-            filter.q = q;
-            filter.calculate_coeff();
-        }
+/// Recalculate a filter's coefficients if its settings changed
+fn update_filter(filter: &mut filter::Biquad, ty: filter::FilterType, freq: f32, q: f32) {
+    if freq != filter.freq || q != filter.q || ty != filter.filter_type {
+        filter.filter_type = ty;
+        filter.freq = freq;
+        filter.q = q;
+        filter.calculate_coeff();
     }
 }
 
@@ -321,7 +1051,12 @@ impl ClapPlugin for Penare {
     const CLAP_SUPPORT_URL: Option<&'static str> = None;
 
     // Don't forget to change these features
-    const CLAP_FEATURES: &'static [ClapFeature] = &[ClapFeature::AudioEffect, ClapFeature::Stereo];
+    const CLAP_FEATURES: &'static [ClapFeature] = &[
+        ClapFeature::AudioEffect,
+        ClapFeature::Stereo,
+        ClapFeature::Mono,
+        ClapFeature::Surround,
+    ];
 }
 
 impl Vst3Plugin for Penare {
@@ -335,5 +1070,5 @@ impl Vst3Plugin for Penare {
         &[Vst3SubCategory::Fx, Vst3SubCategory::Dynamics];
 }
 
-nih_export_clap!(Penare);
+// The CLAP entry point is in `clap`, it adds preset discovery to nih-plug's wrapper
 nih_export_vst3!(Penare);
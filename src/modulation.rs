@@ -0,0 +1,76 @@
+use nih_plug::prelude::*;
+
+/// Parameters that can be modulated. New destinations must be added at the end so
+/// saved states keep pointing at the same parameter
+#[derive(Clone, Copy, Debug, Enum, PartialEq)]
+pub enum ModDestination {
+    #[name = "None"]
+    None,
+    #[name = "Pre Gain"]
+    InputGain,
+    #[name = "Output Gain"]
+    OutputGain,
+    #[name = "Mix"]
+    Mix,
+    #[name = "Output Clip Threshold"]
+    OutputClipThreshold,
+    #[name = "Function Mix"]
+    FunctionMix,
+    #[name = "+ Function Parameter"]
+    PosFunctionParam,
+    #[name = "+ Function Mix"]
+    PosFunctionMix,
+    #[name = "- Function Parameter"]
+    NegFunctionParam,
+    #[name = "- Function Mix"]
+    NegFunctionMix,
+    #[name = "Excess Mix"]
+    ExcessMix,
+    #[name = "Filter 1 Freq"]
+    F1Freq,
+    #[name = "Filter 1 Q"]
+    F1Q,
+    #[name = "Filter 2 Freq"]
+    F2Freq,
+    #[name = "Filter 2 Q"]
+    F2Q,
+    #[name = "Bias"]
+    Bias,
+}
+
+/// Number of [`ModDestination`] variants, the last variant has to stay the last one
+const DESTINATIONS: usize = ModDestination::Bias as usize + 1;
+
+/// Modulation amounts for a single sample, as offsets to the normalized value of
+/// each destination
+#[derive(Clone, Copy)]
+pub struct Modulation {
+    offsets: [f32; DESTINATIONS],
+}
+
+impl Default for Modulation {
+    fn default() -> Self {
+        Self {
+            offsets: [0.0; DESTINATIONS],
+        }
+    }
+}
+
+impl Modulation {
+    /// Add a (normalized) amount of modulation to a destination
+    pub fn add(&mut self, destination: ModDestination, amount: f32) {
+        if destination != ModDestination::None {
+            self.offsets[destination as usize] += amount;
+        }
+    }
+
+    /// Modulate a parameter's value. This only changes the value used for processing,
+    /// the value the host sees stays the same so automation still works
+    pub fn apply(&self, destination: ModDestination, param: &FloatParam, value: f32) -> f32 {
+        let offset = self.offsets[destination as usize];
+        if offset == 0.0 {
+            return value;
+        }
+        param.preview_plain((param.preview_normalized(value) + offset).clamp(0.0, 1.0))
+    }
+}
@@ -1,8 +1,10 @@
 use crate::{
-    fxs::{waveshaper, filter},
+    fxs::{waveshaper, filter, oversample, adaa, lfo, envelope, crossover, stereo, curve, expression, graph, feedback, auto_gain, spectrum},
+    modulation::ModDestination,
     editor,
 };
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use atomic_float::AtomicF32;
 use nih_plug::prelude::*;
 use nih_plug_vizia::ViziaState;
 
@@ -10,7 +12,7 @@ const MAX_FREQ: f32 = 22000.0;
 const MIN_FREQ: f32 = 3.0;
 
 /// A tri-state enum type
-#[derive(Enum, PartialEq)]
+#[derive(Clone, Copy, Enum, PartialEq)]
 pub enum TriState { Off, Pos, Neg }
 
 impl TriState {
@@ -41,6 +43,118 @@ impl std::fmt::Display for TriState {
     }
 }
 
+// Macros for commonly used parameters
+macro_rules! db {
+    ($name:expr, $range:expr) => {
+        FloatParam::new(
+            $name,
+            util::db_to_gain(0.0),
+            FloatRange::Skewed {
+                min: util::db_to_gain(-$range),
+                max: util::db_to_gain($range),
+                factor: FloatRange::gain_skew_factor(-30.0, 30.0),
+            },
+        )
+        .with_smoother(SmoothingStyle::Logarithmic(50.0))
+        .with_unit(" dB")
+        .with_value_to_string(formatters::v2s_f32_gain_to_db(2))
+        .with_string_to_value(formatters::s2v_f32_gain_to_db())
+    }
+}
+macro_rules! hz {
+    ($name:expr, $default:expr) => {
+        FloatParam::new(
+            $name,
+            $default,
+            FloatRange::Skewed {
+                min: MIN_FREQ,
+                max: MAX_FREQ,
+                factor: FloatRange::skew_factor(-1.0),
+            },
+        )
+        .with_smoother(SmoothingStyle::Logarithmic(100.0))
+        .with_value_to_string(formatters::v2s_f32_hz_then_khz(0))
+        .with_string_to_value(formatters::s2v_f32_hz_then_khz())
+    }
+}
+macro_rules! q {
+    ($name:expr, $default:expr) => {
+        FloatParam::new(
+            $name,
+            $default,
+            FloatRange::Skewed {
+                min: 2.0f32.sqrt() / 2.0,
+                max: 10.0,
+                factor: FloatRange::skew_factor(-1.0),
+            },
+        )
+        .with_smoother(SmoothingStyle::Logarithmic(100.0))
+        .with_value_to_string(formatters::v2s_f32_rounded(2))
+    }
+}
+macro_rules! percentage {
+    ($name:expr, $default:expr) => {
+        FloatParam::new(
+            $name,
+            $default,
+            FloatRange::Linear {
+                min: 0.0,
+                max: 1.0,
+            },
+        )
+        .with_smoother(SmoothingStyle::Linear(50.0))
+        .with_unit("%")
+        .with_value_to_string(formatters::v2s_f32_percentage(2))
+        .with_string_to_value(formatters::s2v_f32_percentage())
+    }
+}
+macro_rules! bipolar {
+    ($name:expr, $default:expr) => {
+        FloatParam::new(
+            $name,
+            $default,
+            FloatRange::Linear {
+                min: -1.0,
+                max: 1.0,
+            },
+        )
+        .with_smoother(SmoothingStyle::Linear(50.0))
+        .with_unit("%")
+        .with_value_to_string(formatters::v2s_f32_percentage(2))
+        .with_string_to_value(formatters::s2v_f32_percentage())
+    }
+}
+macro_rules! ms {
+    ($name:expr, $default:expr) => {
+        FloatParam::new(
+            $name,
+            $default,
+            FloatRange::Skewed {
+                min: 0.1,
+                max: 2000.0,
+                factor: FloatRange::skew_factor(-2.0),
+            },
+        )
+        .with_unit(" ms")
+        .with_value_to_string(formatters::v2s_f32_rounded(1))
+    }
+}
+macro_rules! rate {
+    ($name:expr, $default:expr) => {
+        FloatParam::new(
+            $name,
+            $default,
+            FloatRange::Skewed {
+                min: 0.01,
+                max: 20.0,
+                factor: FloatRange::skew_factor(-2.0),
+            },
+        )
+        .with_unit(" Hz")
+        .with_value_to_string(formatters::v2s_f32_rounded(2))
+    }
+}
+
 #[derive(Params)]
 pub struct PenareParams {
     #[persist = "editor-state"]
@@ -69,6 +183,40 @@ pub struct PenareParams {
     /// Output gain after effects
     #[id = "output-gain"]
     pub output_gain: FloatParam,
+    /// Compensate the loudness the distortion adds
+    #[id = "auto-gain"]
+    pub auto_gain: EnumParam<auto_gain::AutoGainMode>,
+    /// How the auto gain measures loudness
+    #[id = "auto-gain-loudness"]
+    pub auto_gain_loudness: EnumParam<auto_gain::Loudness>,
+    /// Turning this on listens to the next few seconds of audio to find the gain
+    /// used by the learned auto gain mode
+    #[id = "auto-gain-learn"]
+    pub auto_gain_learn: BoolParam,
+    /// Gain found by the last learn
+    #[persist = "learned-gain"]
+    pub learned_gain: AtomicF32,
+
+    // ──────────────────────────────
+    // Stereo
+    // ──────────────────────────────
+
+    /// Process the channels linked, as left/right or as mid/side
+    #[id = "stereo-mode"]
+    pub stereo_mode: EnumParam<stereo::StereoMode>,
+    /// Drive and mix of the left/mid and right/side channels, unused when linked
+    #[nested(array, group = "Channel")]
+    pub channels: [ChannelParams; 2],
+    /// Share the waveshaper functions and filters between the channels. When
+    /// unlinked the right/side channel uses its own set
+    #[id = "stereo-link"]
+    pub stereo_link: BoolParam,
+    /// Spread the function parameters and filter frequencies apart between the
+    /// channels, in normalized units
+    #[id = "stereo-offset"]
+    pub stereo_offset: FloatParam,
+    #[nested(id_prefix = "unlinked", group = "Right/Side Channel")]
+    pub unlinked: UnlinkedParams,
 
     // ──────────────────────────────
     // Waveshaper
@@ -77,6 +225,13 @@ pub struct PenareParams {
     /// Mix between dry and wet signal (excluding gain)
     #[id = "function-mix"]
     pub function_mix: FloatParam,
+    /// Offset added before the waveshaper and taken out after it, like a tube's
+    /// operating point
+    #[id = "bias"]
+    pub bias: FloatParam,
+    /// How much the bias drops with the input level
+    #[id = "bias-sag"]
+    pub bias_sag: FloatParam,
     /// Function type to apply to positive shape
     #[id = "pos-function-type"]
     pub pos_function_type: EnumParam<waveshaper::FunctionType>,
@@ -104,12 +259,56 @@ pub struct PenareParams {
     /// Flip the waveshaped signal
     #[id = "flip"]
     pub flip: BoolParam,
+    /// Oversampling factor around the pre-gain, waveshaper and post-gain
+    #[id = "oversampling"]
+    pub oversampling: EnumParam<oversample::OversamplingFactor>,
+    /// Antiderivative anti-aliasing quality
+    #[id = "quality"]
+    pub quality: EnumParam<adaa::Quality>,
+    /// Remove the DC offset left by asymmetric waveshaping
+    #[id = "dc-block"]
+    pub dc_block: BoolParam,
+    /// Number of waveshapers in the chain, the main waveshaper is the first
+    #[id = "stage-count"]
+    pub stage_count: IntParam,
+    #[nested(id_prefix = "stage2", group = "Stage 2")]
+    pub stage2: StageParams,
+    #[nested(id_prefix = "stage3", group = "Stage 3")]
+    pub stage3: StageParams,
+    #[nested(id_prefix = "stage4", group = "Stage 4")]
+    pub stage4: StageParams,
+    /// Control points of the custom function's curve
+    #[persist = "custom-curve"]
+    pub custom_curve: RwLock<curve::Curve>,
+    /// Source of the expression function
+    #[persist = "expression"]
+    pub expression: RwLock<String>,
+    /// The custom curve and expression prepared for the audio thread, needs to be
+    /// updated with [`PenareParams::render_custom_curve`] and
+    /// [`PenareParams::compile_expression`] whenever they change
+    pub user_functions: Arc<waveshaper::UserFunctions>,
+
+    // ──────────────────────────────
+    // Feedback
+    // ──────────────────────────────
+
+    /// Amount of the distortion's output fed back into its input, negative amounts
+    /// invert the fed back signal
+    #[id = "feedback"]
+    pub feedback: FloatParam,
+    /// Delay of the feedback loop
+    #[id = "feedback-delay"]
+    pub feedback_delay: FloatParam,
+    /// Cutoff of the lowpass in the feedback loop
+    #[id = "feedback-damping"]
+    pub feedback_damping: FloatParam,
 
     // ──────────────────────────────
     // Filter
     // ──────────────────────────────
 
-    /// Mix excess signal back into the input
+    /// Mix excess signal back into the input. Not used in patch mode, where the
+    /// excess nodes are routed like any other node
     #[id = "excess-mix"]
     pub excess_mix: FloatParam,
     /// Filter 1 type
@@ -133,75 +332,68 @@ pub struct PenareParams {
     /// Excess signal bypass
     #[id = "excess-bypass"]
     pub excess_bypass: BoolParam,
+
+    // ──────────────────────────────
+    // Multiband
+    // ──────────────────────────────
+
+    /// Split the signal into bands with their own waveshapers instead of using the
+    /// main waveshaper. The extra stages run after each band's waveshaper
+    #[id = "multiband"]
+    pub multiband: BoolParam,
+    /// Number of bands
+    #[id = "band-count"]
+    pub band_count: IntParam,
+    /// Crossover frequencies, from low to high
+    #[id = "crossover-1"]
+    pub crossover_1: FloatParam,
+    #[id = "crossover-2"]
+    pub crossover_2: FloatParam,
+    #[id = "crossover-3"]
+    pub crossover_3: FloatParam,
+    #[nested(array, group = "Band")]
+    pub bands: [BandParams; crossover::MAX_BANDS],
+
+    // ──────────────────────────────
+    // Patch
+    // ──────────────────────────────
+
+    /// Process the signal with the patch instead of the fixed filter, multiband and
+    /// waveshaper path
+    #[id = "patch-mode"]
+    pub patch_mode: BoolParam,
+    /// Routing graph of the patch
+    #[persist = "patch"]
+    pub patch: RwLock<graph::Route>,
+    /// The patch compiled for the audio thread, needs to be updated with
+    /// [`PenareParams::compile_patch`] whenever it changes
+    pub patch_slot: Arc<graph::RouteSlot>,
+
+    // ──────────────────────────────
+    // Spectrum analyser
+    // ──────────────────────────────
+
+    /// Resolution, averaging and peak hold of the spectrum analyser, these are
+    /// editor settings so they aren't exposed to the host
+    #[persist = "analyser"]
+    pub analyser: RwLock<spectrum::Settings>,
+
+    // ──────────────────────────────
+    // Modulation
+    // ──────────────────────────────
+
+    #[nested(id_prefix = "lfo1", group = "LFO 1")]
+    pub lfo1: LfoParams,
+    #[nested(id_prefix = "lfo2", group = "LFO 2")]
+    pub lfo2: LfoParams,
+    #[nested(id_prefix = "env", group = "Envelope Follower")]
+    pub env: EnvelopeParams,
+    #[nested(id_prefix = "adsr", group = "ADSR")]
+    pub adsr: AdsrParams,
 }
 
 impl Default for PenareParams {
     fn default() -> Self {
-        macro_rules! db {
-            ($name:expr, $range:expr) => {
-                FloatParam::new(
-                    $name,
-                    util::db_to_gain(0.0),
-                    FloatRange::Skewed {
-                        min: util::db_to_gain(-$range),
-                        max: util::db_to_gain($range),
-                        factor: FloatRange::gain_skew_factor(-30.0, 30.0),
-                    },
-                )
-                .with_smoother(SmoothingStyle::Logarithmic(50.0))
-                .with_unit(" dB")
-                .with_value_to_string(formatters::v2s_f32_gain_to_db(2))
-                .with_string_to_value(formatters::s2v_f32_gain_to_db())
-            }
-        }
-        macro_rules! hz {
-            ($name:expr, $default:expr) => {
-                FloatParam::new(
-                    $name,
-                    $default,
-                    FloatRange::Skewed {
-                        min: MIN_FREQ,
-                        max: MAX_FREQ,
-                        factor: FloatRange::skew_factor(-1.0),
-                    },
-                )
-                .with_smoother(SmoothingStyle::Logarithmic(100.0))
-                .with_value_to_string(formatters::v2s_f32_hz_then_khz(0))
-                .with_string_to_value(formatters::s2v_f32_hz_then_khz())
-            }
-        }
-        macro_rules! q {
-            ($name:expr, $default:expr) => {
-                FloatParam::new(
-                    $name,
-                    $default,
-                    FloatRange::Skewed {
-                        min: 2.0f32.sqrt() / 2.0,
-                        max: 10.0,
-                        factor: FloatRange::skew_factor(-1.0),
-                    },
-                )
-                .with_smoother(SmoothingStyle::Logarithmic(100.0))
-                .with_value_to_string(formatters::v2s_f32_rounded(2))
-            }
-        }
-        macro_rules! percentage {
-            ($name:expr, $default:expr) => {
-                FloatParam::new(
-                    $name,
-                    $default,
-                    FloatRange::Linear {
-                        min: 0.0,
-                        max: 1.0,
-                    },
-                )
-                .with_smoother(SmoothingStyle::Linear(50.0))
-                .with_unit("%")
-                .with_value_to_string(formatters::v2s_f32_percentage(2))
-                .with_string_to_value(formatters::s2v_f32_percentage())
-            }
-        }
-
         Self {
             editor_state: editor::default_state(),
 
@@ -210,8 +402,20 @@ impl Default for PenareParams {
             output_clip_threshold: db!("Output Clip Threshold", 30.0),
             input_gain:            db!("Pre Gain", 30.0),
             output_gain:           db!("Output Gain", 30.0),
+            auto_gain:             EnumParam::new("Auto Gain", auto_gain::AutoGainMode::Off),
+            auto_gain_loudness:    EnumParam::new("Auto Gain Loudness", auto_gain::Loudness::KWeighted),
+            auto_gain_learn:       BoolParam::new("Auto Gain Learn", false),
+            learned_gain:          AtomicF32::new(1.0),
+
+            stereo_mode: EnumParam::new("Stereo Mode", stereo::StereoMode::Linked),
+            channels:    std::array::from_fn(ChannelParams::new),
+            stereo_link:   BoolParam::new("Stereo Link", true),
+            stereo_offset: percentage!("Stereo Offset", 0.0),
+            unlinked:      UnlinkedParams::default(),
 
             function_mix:       percentage!("Function Mix", 1.0),
+            bias:               bipolar!("Bias", 0.0),
+            bias_sag:           percentage!("Bias Sag", 0.0),
             pos_function_type:  EnumParam::new("Positive Function Type", waveshaper::FunctionType::HardClip),
             pos_function_param: db!("Positive Function Parameter", 30.0),
             pos_function_mix:   percentage!("Positive Function Mix", 1.0),
@@ -221,6 +425,35 @@ impl Default for PenareParams {
             clip_sign:          BoolParam::new("Clip Sign", true),
             copy_function:      EnumParam::new("Copy Function", TriState::Off),
             flip:               BoolParam::new("Flip", false),
+            oversampling:       EnumParam::new("Oversampling", oversample::OversamplingFactor::X1),
+            quality:            EnumParam::new("Quality", adaa::Quality::None),
+            dc_block:           BoolParam::new("DC Blocker", false),
+            stage_count:        IntParam::new(
+                "Stages",
+                1,
+                IntRange::Linear { min: 1, max: waveshaper::MAX_STAGES as i32 },
+            ),
+            stage2:             StageParams::new(2),
+            stage3:             StageParams::new(3),
+            stage4:             StageParams::new(4),
+            custom_curve:       RwLock::new(curve::Curve::default()),
+            expression:         RwLock::new(String::from(expression::DEFAULT_EXPRESSION)),
+            user_functions:     Arc::new(waveshaper::UserFunctions::default()),
+
+            feedback:         bipolar!("Feedback", 0.0),
+            feedback_delay:   FloatParam::new(
+                "Feedback Delay",
+                5.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: feedback::MAX_DELAY_MS,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_unit(" ms")
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+            feedback_damping: hz!("Feedback Damping", 8000.0),
 
             excess_mix:    percentage!("Excess Mix", 0.0),
             f1_type:       EnumParam::new("Filter 1 Type", filter::FilterType::Lowpass),
@@ -230,6 +463,358 @@ impl Default for PenareParams {
             f2_freq:       hz!("Filter 2 Freq", MIN_FREQ),
             f2_q:          q!("Filter 2 Q", 2.0f32.sqrt() / 2.0),
             excess_bypass: BoolParam::new("Excess Bypass", false),
+
+            multiband:   BoolParam::new("Multiband", false),
+            band_count:  IntParam::new(
+                "Band Count",
+                3,
+                IntRange::Linear { min: 2, max: crossover::MAX_BANDS as i32 },
+            ),
+            crossover_1: hz!("Crossover 1", 200.0),
+            crossover_2: hz!("Crossover 2", 2000.0),
+            crossover_3: hz!("Crossover 3", 8000.0),
+            bands:       std::array::from_fn(|i| BandParams::new(i + 1)),
+
+            patch_mode: BoolParam::new("Patch Mode", false),
+            patch:      RwLock::new(graph::Route::default()),
+            patch_slot: Arc::new(graph::RouteSlot::new(&graph::Route::default())),
+
+            analyser: RwLock::new(spectrum::Settings::default()),
+
+            lfo1: LfoParams::new(1),
+            lfo2: LfoParams::new(2),
+            env: EnvelopeParams::default(),
+            adsr: AdsrParams::default(),
+        }
+    }
+}
+
+impl PenareParams {
+    pub fn lfos(&self) -> [&LfoParams; 2] {
+        [&self.lfo1, &self.lfo2]
+    }
+
+    /// Render the custom curve and swap it into the audio thread
+    pub fn render_custom_curve(&self) {
+        if let Ok(curve) = self.custom_curve.read() {
+            self.user_functions.curve.render(&curve);
+        }
+    }
+
+    /// Compile the expression and swap it into the audio thread. The previous
+    /// expression stays in use if it doesn't compile
+    pub fn compile_expression(&self) -> Result<(), expression::ParseError> {
+        let Ok(source) = self.expression.read() else { return Ok(()) };
+        let program = expression::Program::compile(&source)?;
+        self.user_functions.expression.load(&program);
+        Ok(())
+    }
+
+    /// Compile a new expression and swap it into the audio thread. The source is only
+    /// stored if it compiles, so the saved state always holds a working expression
+    pub fn set_expression(&self, source: &str) -> Result<(), expression::ParseError> {
+        let program = expression::Program::compile(source)?;
+        if let Ok(mut expression) = self.expression.write() {
+            *expression = source.to_string();
+        }
+        self.user_functions.expression.load(&program);
+        Ok(())
+    }
+
+    /// Compile the patch and swap it into the audio thread
+    pub fn compile_patch(&self) {
+        if let Ok(patch) = self.patch.read() {
+            self.patch_slot.load(&patch);
+        }
+    }
+
+    /// The waveshapers after the main one
+    pub fn stages(&self) -> [&StageParams; waveshaper::MAX_STAGES - 1] {
+        [&self.stage2, &self.stage3, &self.stage4]
+    }
+
+    /// Parameters of a waveshaper stage in a fixed order, stage 0 is the main
+    /// waveshaper. Used to move stages around in the chain
+    pub fn stage_ptrs(&self, stage: usize) -> [ParamPtr; 10] {
+        if stage == 0 {
+            return [
+                self.pos_function_type.as_ptr(),
+                self.pos_function_param.as_ptr(),
+                self.pos_function_mix.as_ptr(),
+                self.neg_function_type.as_ptr(),
+                self.neg_function_param.as_ptr(),
+                self.neg_function_mix.as_ptr(),
+                self.clip_sign.as_ptr(),
+                self.copy_function.as_ptr(),
+                self.flip.as_ptr(),
+                self.function_mix.as_ptr(),
+            ];
+        }
+        let stage = self.stages()[stage - 1];
+        [
+            stage.pos_function_type.as_ptr(),
+            stage.pos_function_param.as_ptr(),
+            stage.pos_function_mix.as_ptr(),
+            stage.neg_function_type.as_ptr(),
+            stage.neg_function_param.as_ptr(),
+            stage.neg_function_mix.as_ptr(),
+            stage.clip_sign.as_ptr(),
+            stage.copy_function.as_ptr(),
+            stage.flip.as_ptr(),
+            stage.function_mix.as_ptr(),
+        ]
+    }
+
+    pub fn crossovers(&self) -> [&FloatParam; crossover::MAX_SPLITS] {
+        [&self.crossover_1, &self.crossover_2, &self.crossover_3]
+    }
+}
+
+#[derive(Params)]
+pub struct ChannelParams {
+    /// Gain before the waveshaper, on top of the pre gain
+    #[id = "channel-drive"]
+    pub drive: FloatParam,
+    /// Scales the dry/wet mix of the channel
+    #[id = "channel-mix"]
+    pub mix: FloatParam,
+}
+
+impl ChannelParams {
+    fn new(index: usize) -> Self {
+        let name = ["Mid/Left", "Side/Right"][index];
+        Self {
+            drive: db!(format!("{} Drive", name), 30.0),
+            mix:   percentage!(format!("{} Mix", name), 1.0),
+        }
+    }
+}
+
+/// A waveshaper after the main one in the chain, with the same settings
+#[derive(Params)]
+pub struct StageParams {
+    #[id = "pos-function-type"]
+    pub pos_function_type: EnumParam<waveshaper::FunctionType>,
+    #[id = "pos-function-param"]
+    pub pos_function_param: FloatParam,
+    #[id = "pos-function-mix"]
+    pub pos_function_mix: FloatParam,
+    #[id = "neg-function-type"]
+    pub neg_function_type: EnumParam<waveshaper::FunctionType>,
+    #[id = "neg-function-param"]
+    pub neg_function_param: FloatParam,
+    #[id = "neg-function-mix"]
+    pub neg_function_mix: FloatParam,
+    #[id = "clip-sign"]
+    pub clip_sign: BoolParam,
+    #[id = "copy-function"]
+    pub copy_function: EnumParam<TriState>,
+    #[id = "flip"]
+    pub flip: BoolParam,
+    #[id = "function-mix"]
+    pub function_mix: FloatParam,
+}
+
+impl StageParams {
+    fn new(index: usize) -> Self {
+        Self {
+            pos_function_type:  EnumParam::new(format!("Stage {} Positive Function Type", index), waveshaper::FunctionType::HardClip),
+            pos_function_param: db!(format!("Stage {} Positive Function Parameter", index), 30.0),
+            pos_function_mix:   percentage!(format!("Stage {} Positive Function Mix", index), 1.0),
+            neg_function_type:  EnumParam::new(format!("Stage {} Negative Function Type", index), waveshaper::FunctionType::HardClip),
+            neg_function_param: db!(format!("Stage {} Negative Function Parameter", index), 30.0),
+            neg_function_mix:   percentage!(format!("Stage {} Negative Function Mix", index), 1.0),
+            clip_sign:          BoolParam::new(format!("Stage {} Clip Sign", index), true),
+            copy_function:      EnumParam::new(format!("Stage {} Copy Function", index), TriState::Off),
+            flip:               BoolParam::new(format!("Stage {} Flip", index), false),
+            function_mix:       percentage!(format!("Stage {} Function Mix", index), 1.0),
+        }
+    }
+}
+
+/// Waveshaper function and filter parameters of the right/side channel when the
+/// channels are unlinked
+#[derive(Params)]
+pub struct UnlinkedParams {
+    #[id = "pos-function-type"]
+    pub pos_function_type: EnumParam<waveshaper::FunctionType>,
+    #[id = "pos-function-param"]
+    pub pos_function_param: FloatParam,
+    #[id = "neg-function-type"]
+    pub neg_function_type: EnumParam<waveshaper::FunctionType>,
+    #[id = "neg-function-param"]
+    pub neg_function_param: FloatParam,
+    #[id = "f1-type"]
+    pub f1_type: EnumParam<filter::FilterType>,
+    #[id = "f1-freq"]
+    pub f1_freq: FloatParam,
+    #[id = "f1-q"]
+    pub f1_q: FloatParam,
+    #[id = "f2-type"]
+    pub f2_type: EnumParam<filter::FilterType>,
+    #[id = "f2-freq"]
+    pub f2_freq: FloatParam,
+    #[id = "f2-q"]
+    pub f2_q: FloatParam,
+}
+
+impl Default for UnlinkedParams {
+    fn default() -> Self {
+        Self {
+            pos_function_type:  EnumParam::new("Side/Right Positive Function Type", waveshaper::FunctionType::HardClip),
+            pos_function_param: db!("Side/Right Positive Function Parameter", 30.0),
+            neg_function_type:  EnumParam::new("Side/Right Negative Function Type", waveshaper::FunctionType::HardClip),
+            neg_function_param: db!("Side/Right Negative Function Parameter", 30.0),
+            f1_type:            EnumParam::new("Side/Right Filter 1 Type", filter::FilterType::Lowpass),
+            f1_freq:            hz!("Side/Right Filter 1 Freq", MAX_FREQ),
+            f1_q:               q!("Side/Right Filter 1 Q", 2.0f32.sqrt() / 2.0),
+            f2_type:            EnumParam::new("Side/Right Filter 2 Type", filter::FilterType::Highpass),
+            f2_freq:            hz!("Side/Right Filter 2 Freq", MIN_FREQ),
+            f2_q:               q!("Side/Right Filter 2 Q", 2.0f32.sqrt() / 2.0),
+        }
+    }
+}
+
+#[derive(Params)]
+pub struct BandParams {
+    /// Gain before the band's waveshaper
+    #[id = "band-gain"]
+    pub gain: FloatParam,
+    #[id = "band-pos-function-type"]
+    pub pos_function_type: EnumParam<waveshaper::FunctionType>,
+    #[id = "band-pos-function-param"]
+    pub pos_function_param: FloatParam,
+    #[id = "band-neg-function-type"]
+    pub neg_function_type: EnumParam<waveshaper::FunctionType>,
+    #[id = "band-neg-function-param"]
+    pub neg_function_param: FloatParam,
+    /// Mix between the band and its waveshaped signal
+    #[id = "band-mix"]
+    pub mix: FloatParam,
+    #[id = "band-solo"]
+    pub solo: BoolParam,
+    #[id = "band-mute"]
+    pub mute: BoolParam,
+    /// Gain after the band's waveshaper
+    #[id = "band-level"]
+    pub level: FloatParam,
+}
+
+impl BandParams {
+    fn new(index: usize) -> Self {
+        Self {
+            gain:               db!(format!("Band {} Gain", index), 30.0),
+            pos_function_type:  EnumParam::new(format!("Band {} Positive Function Type", index), waveshaper::FunctionType::HardClip),
+            pos_function_param: db!(format!("Band {} Positive Function Parameter", index), 30.0),
+            neg_function_type:  EnumParam::new(format!("Band {} Negative Function Type", index), waveshaper::FunctionType::HardClip),
+            neg_function_param: db!(format!("Band {} Negative Function Parameter", index), 30.0),
+            mix:                percentage!(format!("Band {} Mix", index), 1.0),
+            solo:               BoolParam::new(format!("Band {} Solo", index), false),
+            mute:               BoolParam::new(format!("Band {} Mute", index), false),
+            level:              db!(format!("Band {} Level", index), 30.0),
+        }
+    }
+}
+
+#[derive(Params)]
+pub struct LfoParams {
+    /// Waveform of the LFO
+    #[id = "shape"]
+    pub shape: EnumParam<lfo::LfoShape>,
+    /// Follow the host's tempo and transport instead of the free-running rate
+    #[id = "sync"]
+    pub sync: BoolParam,
+    /// Free-running rate
+    #[id = "rate"]
+    pub rate: FloatParam,
+    /// Tempo-synced rate
+    #[id = "sync-rate"]
+    pub sync_rate: EnumParam<lfo::SyncRate>,
+    /// How much the LFO moves the destination, in normalized units
+    #[id = "depth"]
+    pub depth: FloatParam,
+    /// Parameter to modulate
+    #[id = "destination"]
+    pub destination: EnumParam<ModDestination>,
+}
+
+impl LfoParams {
+    fn new(index: usize) -> Self {
+        Self {
+            shape:       EnumParam::new(format!("LFO {} Shape", index), lfo::LfoShape::Sine),
+            sync:        BoolParam::new(format!("LFO {} Sync", index), false),
+            rate:        rate!(format!("LFO {} Rate", index), 1.0),
+            sync_rate:   EnumParam::new(format!("LFO {} Sync Rate", index), lfo::SyncRate::Quarter),
+            depth:       bipolar!(format!("LFO {} Depth", index), 0.0),
+            destination: EnumParam::new(format!("LFO {} Destination", index), ModDestination::None),
+        }
+    }
+}
+
+#[derive(Params)]
+pub struct EnvelopeParams {
+    /// Follow the main input or the sidechain input
+    #[id = "source"]
+    pub source: EnumParam<envelope::EnvelopeSource>,
+    #[id = "attack"]
+    pub attack: FloatParam,
+    #[id = "release"]
+    pub release: FloatParam,
+    /// Follow the peak or the RMS level
+    #[id = "mode"]
+    pub mode: EnumParam<envelope::DetectorMode>,
+    /// How much the envelope moves the destination, in normalized units
+    #[id = "amount"]
+    pub amount: FloatParam,
+    /// Parameter to modulate
+    #[id = "destination"]
+    pub destination: EnumParam<ModDestination>,
+}
+
+impl Default for EnvelopeParams {
+    fn default() -> Self {
+        Self {
+            source:      EnumParam::new("Envelope Source", envelope::EnvelopeSource::Input),
+            attack:      ms!("Envelope Attack", 10.0),
+            release:     ms!("Envelope Release", 100.0),
+            mode:        EnumParam::new("Envelope Mode", envelope::DetectorMode::Peak),
+            amount:      bipolar!("Envelope Amount", 0.0),
+            destination: EnumParam::new("Envelope Destination", ModDestination::None),
+        }
+    }
+}
+
+#[derive(Params)]
+pub struct AdsrParams {
+    #[id = "attack"]
+    pub attack: FloatParam,
+    #[id = "decay"]
+    pub decay: FloatParam,
+    #[id = "sustain"]
+    pub sustain: FloatParam,
+    #[id = "release"]
+    pub release: FloatParam,
+    /// How much the note velocity scales the envelope
+    #[id = "velocity"]
+    pub velocity: FloatParam,
+    /// How much the envelope moves the destination, in normalized units
+    #[id = "amount"]
+    pub amount: FloatParam,
+    /// Parameter to modulate
+    #[id = "destination"]
+    pub destination: EnumParam<ModDestination>,
+}
+
+impl Default for AdsrParams {
+    fn default() -> Self {
+        Self {
+            attack:      ms!("ADSR Attack", 5.0),
+            decay:       ms!("ADSR Decay", 200.0),
+            sustain:     percentage!("ADSR Sustain", 0.7),
+            release:     ms!("ADSR Release", 100.0),
+            velocity:    percentage!("ADSR Velocity", 1.0),
+            amount:      bipolar!("ADSR Amount", 0.0),
+            destination: EnumParam::new("ADSR Destination", ModDestination::None),
         }
     }
 }
\ No newline at end of file
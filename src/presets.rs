@@ -0,0 +1,304 @@
+use crate::params::PenareParams;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+use nih_plug::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Current version of the preset file format. Bump this when the format changes in a
+/// way older versions of the plugin can't read
+pub const PRESET_VERSION: u32 = 1;
+/// File extension of preset files
+pub const PRESET_EXTENSION: &str = "json";
+/// Persistent fields that shouldn't be stored in presets
+const IGNORED_FIELDS: &[&str] = &["editor-state", "analyser"];
+
+/// Factory presets compiled into the plugin
+const FACTORY_PRESETS: &[&str] = &[
+    include_str!("../assets/presets/tape-ish-warmth.json"),
+    include_str!("../assets/presets/soft-tube.json"),
+    include_str!("../assets/presets/brickwall.json"),
+    include_str!("../assets/presets/wavefolder.json"),
+    include_str!("../assets/presets/silly-folds.json"),
+    include_str!("../assets/presets/broken-radio.json"),
+    include_str!("../assets/presets/8-bit.json"),
+];
+
+/// A parameter value the way nih-plug stores it in the plugin state
+#[derive(Serialize)]
+#[serde(untagged)]
+enum StateValue {
+    F32(f32),
+    I32(i32),
+    Bool(bool),
+}
+
+/// The plugin state nih-plug's wrapper loads, used to load presets through the host
+#[derive(Serialize)]
+struct PluginState<'a> {
+    version: &'a str,
+    params: BTreeMap<String, StateValue>,
+    fields: &'a BTreeMap<String, String>,
+}
+
+#[derive(Debug)]
+pub enum PresetError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    /// The preset was saved by a newer version of the plugin
+    Version(u32),
+    /// There is no user directory to store presets in
+    NoDirectory,
+}
+
+impl std::fmt::Display for PresetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PresetError::Io(e)       => write!(f, "{}", e),
+            PresetError::Json(e)     => write!(f, "invalid preset: {}", e),
+            PresetError::Version(v)  => write!(f, "preset version {} is newer than {}", v, PRESET_VERSION),
+            PresetError::NoDirectory => write!(f, "no preset directory"),
+        }
+    }
+}
+
+impl From<io::Error> for PresetError {
+    fn from(e: io::Error) -> Self {
+        PresetError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for PresetError {
+    fn from(e: serde_json::Error) -> Self {
+        PresetError::Json(e)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Preset {
+    pub version: u32,
+    pub name: String,
+    /// Category to group the preset under, only used by factory presets
+    #[serde(default)]
+    pub category: String,
+    /// Plain parameter values keyed by the parameter's ID, enums are stored as
+    /// their variant index and booleans as 0 or 1
+    pub params: BTreeMap<String, f32>,
+    /// Persistent fields keyed by their `#[persist]` key
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+}
+
+impl Preset {
+    /// Take a snapshot of the current parameters
+    pub fn from_params(name: &str, params: &PenareParams) -> Self {
+        let values = params.param_map()
+            .into_iter()
+            .map(|(id, ptr, _)| (id, unsafe { ptr.unmodulated_plain_value() }))
+            .collect();
+        let mut fields = params.serialize_fields();
+        for field in IGNORED_FIELDS {
+            fields.remove(*field);
+        }
+        Self {
+            version: PRESET_VERSION,
+            name: name.to_string(),
+            category: String::new(),
+            params: values,
+            fields,
+        }
+    }
+
+    /// Normalized value of every parameter, parameters missing from the preset get
+    /// their default value
+    pub fn values(&self, params: &PenareParams) -> Vec<(ParamPtr, f32)> {
+        params.param_map()
+            .into_iter()
+            .map(|(id, ptr, _)| {
+                let value = match self.params.get(&id) {
+                    Some(plain) => unsafe { ptr.preview_normalized(*plain) },
+                    None        => unsafe { ptr.default_normalized_value() },
+                };
+                (ptr, value)
+            })
+            .collect()
+    }
+
+    /// The preset as a plugin state nih-plug can load, parameters missing from the
+    /// preset get their default value
+    pub fn to_state(&self, params: &PenareParams) -> Result<Vec<u8>, PresetError> {
+        let values = params.param_map()
+            .into_iter()
+            .map(|(id, ptr, _)| {
+                let plain = match self.params.get(&id) {
+                    Some(plain) => *plain,
+                    None        => unsafe { ptr.preview_plain(ptr.default_normalized_value()) },
+                };
+                let value = match ptr {
+                    ParamPtr::FloatParam(_) => StateValue::F32(plain),
+                    ParamPtr::IntParam(_) | ParamPtr::EnumParam(_) => StateValue::I32(plain.round() as i32),
+                    ParamPtr::BoolParam(_) => StateValue::Bool(plain >= 0.5),
+                };
+                (id, value)
+            })
+            .collect();
+        Ok(serde_json::to_vec(&PluginState {
+            version: env!("CARGO_PKG_VERSION"),
+            params: values,
+            fields: &self.fields,
+        })?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, PresetError> {
+        let preset: Preset = serde_json::from_str(json)?;
+        if preset.version > PRESET_VERSION {
+            return Err(PresetError::Version(preset.version));
+        }
+        Ok(preset)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, PresetError> {
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+
+    /// Save the preset to the user preset directory, overwriting any preset with
+    /// the same name
+    pub fn save(&self) -> Result<(), PresetError> {
+        let dir = preset_dir().ok_or(PresetError::NoDirectory)?;
+        fs::create_dir_all(&dir)?;
+        fs::write(preset_path(&dir, &self.name), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// A preset in the browser, either from the factory bank or the user directory
+#[derive(Clone, Debug, PartialEq)]
+pub enum PresetEntry {
+    /// Index into [`factory`]
+    Factory(usize),
+    /// Name of the user preset
+    User(String),
+}
+
+impl PresetEntry {
+    /// Name shown in the preset browser
+    pub fn display_name(&self) -> String {
+        match self {
+            PresetEntry::Factory(i) => {
+                let preset = &factory()[*i];
+                format!("{}: {}", preset.category, preset.name)
+            },
+            PresetEntry::User(name) => name.clone(),
+        }
+    }
+
+    /// Factory presets can't be overwritten or deleted
+    pub fn is_read_only(&self) -> bool {
+        matches!(self, PresetEntry::Factory(_))
+    }
+
+    pub fn load(&self) -> Result<Preset, PresetError> {
+        match self {
+            PresetEntry::Factory(i) => Ok(factory()[*i].clone()),
+            PresetEntry::User(name) => load(name),
+        }
+    }
+}
+
+/// The factory presets, sorted by category
+pub fn factory() -> &'static [Preset] {
+    static FACTORY: OnceLock<Vec<Preset>> = OnceLock::new();
+    FACTORY.get_or_init(|| {
+        let mut presets = FACTORY_PRESETS.iter()
+            .map(|json| Preset::from_json(json).expect("invalid factory preset"))
+            .collect::<Vec<_>>();
+        // Stable sort, so presets keep their order within a category
+        presets.sort_by(|a, b| a.category.cmp(&b.category));
+        presets
+    })
+}
+
+/// Every preset in the browser, factory presets first
+pub fn entries() -> Vec<PresetEntry> {
+    (0..factory().len())
+        .map(PresetEntry::Factory)
+        .chain(list().into_iter().map(PresetEntry::User))
+        .collect()
+}
+
+/// Directory of the user presets
+pub fn preset_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("Penare").join("Presets"))
+}
+
+fn preset_path(dir: &Path, name: &str) -> PathBuf {
+    // Don't let the name escape the preset directory
+    let name = name.replace(['/', '\\', ':'], "_");
+    dir.join(format!("{}.{}", name, PRESET_EXTENSION))
+}
+
+/// Names of the user presets, sorted alphabetically
+pub fn list() -> Vec<String> {
+    let Some(dir) = preset_dir() else { return vec![] };
+    let Ok(entries) = fs::read_dir(dir) else { return vec![] };
+    let mut names = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == PRESET_EXTENSION))
+        .filter_map(|path| path.file_stem().map(|s| s.to_string_lossy().into_owned()))
+        .collect::<Vec<_>>();
+    names.sort_by_key(|name| name.to_lowercase());
+    names
+}
+
+/// Load a user preset by name
+pub fn load(name: &str) -> Result<Preset, PresetError> {
+    let dir = preset_dir().ok_or(PresetError::NoDirectory)?;
+    Preset::load(&preset_path(&dir, name))
+}
+
+/// Delete a user preset by name
+pub fn delete(name: &str) -> Result<(), PresetError> {
+    let dir = preset_dir().ok_or(PresetError::NoDirectory)?;
+    fs::remove_file(preset_path(&dir, name))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn factory_presets_parse() {
+        let params = PenareParams::default();
+        let ids = params.param_map().into_iter().map(|(id, _, _)| id).collect::<Vec<_>>();
+        for preset in factory() {
+            assert!(!preset.category.is_empty(), "{} has no category", preset.name);
+            for id in preset.params.keys() {
+                assert!(ids.contains(id), "{} has unknown parameter {}", preset.name, id);
+            }
+        }
+    }
+
+    #[test]
+    fn factory_presets_to_state() {
+        let params = PenareParams::default();
+        for preset in factory() {
+            let state: serde_json::Value = serde_json::from_slice(&preset.to_state(&params).unwrap()).unwrap();
+            for (id, ptr, _) in params.param_map() {
+                let value = &state["params"][&id];
+                let valid = match ptr {
+                    ParamPtr::FloatParam(_) => value.is_f64(),
+                    ParamPtr::IntParam(_) | ParamPtr::EnumParam(_) => value.is_i64(),
+                    ParamPtr::BoolParam(_) => value.is_boolean(),
+                };
+                assert!(valid, "{} stores {} as {}", preset.name, id, value);
+            }
+            assert_eq!(state["fields"].as_object().unwrap().len(), preset.fields.len());
+        }
+    }
+}